@@ -0,0 +1,651 @@
+use crate::{
+    chunk::{Handler, Instruction, Value},
+    error::LoxError,
+    gc::{Gc, GcRef},
+    objects::{Function, FunctionUpvalue, LocalVariable},
+    scanner::Span,
+};
+
+/// Every bytecode file starts with these bytes.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+
+/// Bumped every time the layout of the format changes. Files written with a
+/// different version are rejected instead of being misinterpreted.
+pub const VERSION: u16 = 12;
+
+const CONSTANT_NIL: u8 = 0;
+const CONSTANT_BOOL: u8 = 1;
+const CONSTANT_NUMBER: u8 = 2;
+const CONSTANT_STRING: u8 = 3;
+const CONSTANT_FUNCTION: u8 = 4;
+
+/// Writes a compiled script, including all the functions nested in its
+/// constants, into the binary bytecode format.
+pub fn serialize_function(function: GcRef<Function>, gc: &Gc) -> Vec<u8> {
+    let mut writer = Writer {
+        gc,
+        bytes: Vec::new(),
+    };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u16(VERSION);
+    writer.function(function);
+    writer.bytes
+}
+
+/// Reconstructs a script written by `serialize_function`, allocating its
+/// functions and strings in `gc`.
+pub fn deserialize_function(bytes: &[u8], gc: &mut Gc) -> Result<GcRef<Function>, LoxError> {
+    let mut reader = Reader {
+        gc,
+        bytes,
+        position: 0,
+    };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return reader.error("Not a Lox bytecode file.");
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        let msg = format!(
+            "Unsupported bytecode version {} (expected {}).",
+            version, VERSION
+        );
+        return reader.error(&msg);
+    }
+    let function = reader.function()?;
+    // The script runs in a closure without upvalues.
+    if !reader.gc.deref(function).upvalues.is_empty() {
+        return reader.error("Upvalue index out of range.");
+    }
+    if reader.position != bytes.len() {
+        return reader.error("Unexpected data after the end of the script.");
+    }
+    Ok(function)
+}
+
+/// Returns true if the bytes look like a serialized script rather than
+/// source code.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+struct Writer<'gc> {
+    gc: &'gc Gc,
+    bytes: Vec<u8>,
+}
+
+impl<'gc> Writer<'gc> {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: usize) {
+        let value = u32::try_from(value).expect("Value too large for bytecode format");
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, string: &str) {
+        self.u32(string.len());
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    fn string(&mut self, reference: GcRef<String>) {
+        let gc = self.gc;
+        self.str(gc.deref::<String>(reference));
+    }
+
+    fn function(&mut self, reference: GcRef<Function>) {
+        let function = self.gc.deref(reference);
+        self.string(function.name);
+        self.u32(function.arity);
+
+        self.u32(function.upvalues.len());
+        for upvalue in &function.upvalues {
+            self.u16(upvalue.index);
+            self.u8(upvalue.is_local as u8);
+        }
+
+        let chunk = &function.chunk;
+        self.u32(chunk.code.len());
+        for &instruction in &chunk.code {
+            self.instruction(instruction);
+        }
+        for &line in &chunk.lines {
+            self.u32(line);
+        }
+        for span in &chunk.spans {
+            self.u32(span.start);
+            self.u32(span.end);
+        }
+
+        self.u32(chunk.constants.len());
+        for &constant in &chunk.constants {
+            self.constant(constant);
+        }
+
+        self.u32(chunk.handlers.len());
+        for handler in &chunk.handlers {
+            self.u32(handler.start);
+            self.u32(handler.end);
+            self.u32(handler.target);
+            self.u32(handler.depth);
+        }
+
+        self.u32(function.locals.len());
+        for local in &function.locals {
+            self.str(&local.name);
+            self.u32(local.slot);
+            self.u32(local.start);
+            self.u32(local.end);
+        }
+        for name in &function.upvalue_names {
+            self.str(name);
+        }
+    }
+
+    fn constant(&mut self, value: Value) {
+        match value {
+            Value::Nil => self.u8(CONSTANT_NIL),
+            Value::Bool(b) => {
+                self.u8(CONSTANT_BOOL);
+                self.u8(b as u8);
+            }
+            Value::Number(n) => {
+                self.u8(CONSTANT_NUMBER);
+                self.f64(n);
+            }
+            Value::String(s) => {
+                self.u8(CONSTANT_STRING);
+                self.string(s);
+            }
+            Value::Function(f) => {
+                self.u8(CONSTANT_FUNCTION);
+                self.function(f);
+            }
+            _ => panic!("Constant can't be serialized: {:?}", value),
+        }
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Add => self.u8(0),
+            Instruction::BuildList(count) => self.op_u16(1, count),
+            Instruction::BuildMap(count) => self.op_u16(2, count),
+            Instruction::BuildString(count) => self.op_u16(41, count),
+            Instruction::Call(args) => self.op_u16(3, args),
+            Instruction::Class(c) => self.op_u16(4, c),
+            Instruction::CloseUpvalue => self.u8(5),
+            Instruction::Closure(c) => self.op_u16(6, c),
+            Instruction::Constant(c) => self.op_u16(7, c),
+            Instruction::DefineConstGlobal(c) => self.op_u16(48, c),
+            Instruction::DefineGlobal(c) => self.op_u16(8, c),
+            Instruction::Divide => self.u8(9),
+            Instruction::Equal => self.u8(10),
+            Instruction::Export(c) => self.op_u16(46, c),
+            Instruction::False => self.u8(11),
+            Instruction::FloorDivide => self.u8(42),
+            Instruction::GetGlobal(c) => self.op_u16(12, c),
+            Instruction::GetIndex => self.u8(13),
+            Instruction::GetLocal(s) => self.op_u16(14, s),
+            Instruction::GetProperty(c) => self.op_u16(15, c),
+            Instruction::GetSuper(c) => self.op_u16(16, c),
+            Instruction::GetUpvalue(s) => self.op_u16(17, s),
+            Instruction::Greater => self.u8(18),
+            Instruction::Import(c) => self.op_u16(47, c),
+            Instruction::Inherit => self.u8(19),
+            Instruction::Invoke((c, args)) => {
+                self.op_u16(20, c);
+                self.u16(args);
+            }
+            Instruction::Jump(offset) => self.op_u16(21, offset),
+            Instruction::JumpIfFalse(offset) => self.op_u16(22, offset),
+            Instruction::Less => self.u8(23),
+            Instruction::Loop(offset) => self.op_u16(24, offset),
+            Instruction::Method(c) => self.op_u16(25, c),
+            Instruction::Multiply => self.u8(26),
+            Instruction::Modulo => self.u8(43),
+            Instruction::Negate => self.u8(27),
+            Instruction::Nil => self.u8(28),
+            Instruction::Not => self.u8(29),
+            Instruction::Pop => self.u8(30),
+            Instruction::Power => self.u8(44),
+            Instruction::Print => self.u8(31),
+            Instruction::Return => self.u8(32),
+            Instruction::SetGlobal(c) => self.op_u16(33, c),
+            Instruction::SetIndex => self.u8(34),
+            Instruction::SetLocal(s) => self.op_u16(35, s),
+            Instruction::SetProperty(c) => self.op_u16(36, c),
+            Instruction::SetUpvalue(s) => self.op_u16(37, s),
+            Instruction::Substract => self.u8(38),
+            Instruction::SuperInvoke((c, args)) => {
+                self.op_u16(39, c);
+                self.u16(args);
+            }
+            Instruction::Throw => self.u8(45),
+            Instruction::True => self.u8(40),
+        }
+    }
+
+    fn op_u16(&mut self, opcode: u8, operand: u16) {
+        self.u8(opcode);
+        self.u16(operand);
+    }
+}
+
+struct Reader<'a> {
+    gc: &'a mut Gc,
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T, LoxError> {
+        Err(LoxError::BytecodeError(format!(
+            "Invalid bytecode at byte {}: {}",
+            self.position, msg
+        )))
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], LoxError> {
+        match self.bytes.get(self.position..self.position + count) {
+            Some(bytes) => {
+                self.position += count;
+                Ok(bytes)
+            }
+            None => self.error("Unexpected end of file."),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, LoxError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoxError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize, LoxError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, LoxError> {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(buffer))
+    }
+
+    fn bool(&mut self) -> Result<bool, LoxError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => self.error("Invalid boolean."),
+        }
+    }
+
+    fn str(&mut self) -> Result<String, LoxError> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Ok(s.to_owned()),
+            Err(_) => self.error("Invalid UTF-8 string."),
+        }
+    }
+
+    fn string(&mut self) -> Result<GcRef<String>, LoxError> {
+        let string = self.str()?;
+        Ok(self.gc.intern(string))
+    }
+
+    fn function(&mut self) -> Result<GcRef<Function>, LoxError> {
+        let name = self.string()?;
+        let mut function = Function::new(name);
+        function.arity = self.u32()?;
+
+        let upvalue_count = self.u32()?;
+        for _ in 0..upvalue_count {
+            let index = self.u16()?;
+            let is_local = self.bool()?;
+            function.upvalues.push(FunctionUpvalue { index, is_local });
+        }
+
+        let chunk = &mut function.chunk;
+        let code_len = self.u32()?;
+        for _ in 0..code_len {
+            let instruction = self.instruction()?;
+            chunk.code.push(instruction);
+        }
+        for _ in 0..code_len {
+            let line = self.u32()?;
+            chunk.lines.push(line);
+        }
+        for _ in 0..code_len {
+            let start = self.u32()?;
+            let end = self.u32()?;
+            chunk.spans.push(Span::new(start, end));
+        }
+
+        let constant_count = self.u32()?;
+        for _ in 0..constant_count {
+            let constant = self.constant()?;
+            chunk.constants.push(constant);
+        }
+
+        let handler_count = self.u32()?;
+        for _ in 0..handler_count {
+            let handler = Handler {
+                start: self.u32()?,
+                end: self.u32()?,
+                target: self.u32()?,
+                depth: self.u32()?,
+            };
+            if handler.start > handler.end || handler.end > code_len || handler.target >= code_len {
+                return self.error("Exception handler out of range.");
+            }
+            chunk.handlers.push(handler);
+        }
+
+        let local_count = self.u32()?;
+        for _ in 0..local_count {
+            let name = self.str()?;
+            let slot = self.u32()?;
+            let start = self.u32()?;
+            let end = self.u32()?;
+            function.locals.push(LocalVariable {
+                name,
+                slot,
+                start,
+                end,
+            });
+        }
+        for _ in 0..upvalue_count {
+            let name = self.str()?;
+            function.upvalue_names.push(name);
+        }
+
+        if let Err(msg) = verify(&function, self.gc) {
+            return self.error(msg);
+        }
+        Ok(self.gc.alloc(function))
+    }
+
+    fn constant(&mut self) -> Result<Value, LoxError> {
+        match self.u8()? {
+            CONSTANT_NIL => Ok(Value::Nil),
+            CONSTANT_BOOL => Ok(Value::Bool(self.bool()?)),
+            CONSTANT_NUMBER => Ok(Value::Number(self.f64()?)),
+            CONSTANT_STRING => Ok(Value::String(self.string()?)),
+            CONSTANT_FUNCTION => Ok(Value::Function(self.function()?)),
+            _ => self.error("Unknown constant type."),
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, LoxError> {
+        let instruction = match self.u8()? {
+            0 => Instruction::Add,
+            1 => Instruction::BuildList(self.u16()?),
+            2 => Instruction::BuildMap(self.u16()?),
+            3 => Instruction::Call(self.u16()?),
+            4 => Instruction::Class(self.u16()?),
+            5 => Instruction::CloseUpvalue,
+            6 => Instruction::Closure(self.u16()?),
+            7 => Instruction::Constant(self.u16()?),
+            8 => Instruction::DefineGlobal(self.u16()?),
+            9 => Instruction::Divide,
+            10 => Instruction::Equal,
+            11 => Instruction::False,
+            12 => Instruction::GetGlobal(self.u16()?),
+            13 => Instruction::GetIndex,
+            14 => Instruction::GetLocal(self.u16()?),
+            15 => Instruction::GetProperty(self.u16()?),
+            16 => Instruction::GetSuper(self.u16()?),
+            17 => Instruction::GetUpvalue(self.u16()?),
+            18 => Instruction::Greater,
+            19 => Instruction::Inherit,
+            20 => Instruction::Invoke((self.u16()?, self.u16()?)),
+            21 => Instruction::Jump(self.u16()?),
+            22 => Instruction::JumpIfFalse(self.u16()?),
+            23 => Instruction::Less,
+            24 => Instruction::Loop(self.u16()?),
+            25 => Instruction::Method(self.u16()?),
+            26 => Instruction::Multiply,
+            27 => Instruction::Negate,
+            28 => Instruction::Nil,
+            29 => Instruction::Not,
+            30 => Instruction::Pop,
+            31 => Instruction::Print,
+            32 => Instruction::Return,
+            33 => Instruction::SetGlobal(self.u16()?),
+            34 => Instruction::SetIndex,
+            35 => Instruction::SetLocal(self.u16()?),
+            36 => Instruction::SetProperty(self.u16()?),
+            37 => Instruction::SetUpvalue(self.u16()?),
+            38 => Instruction::Substract,
+            39 => Instruction::SuperInvoke((self.u16()?, self.u16()?)),
+            40 => Instruction::True,
+            41 => Instruction::BuildString(self.u16()?),
+            42 => Instruction::FloorDivide,
+            43 => Instruction::Modulo,
+            44 => Instruction::Power,
+            45 => Instruction::Throw,
+            46 => Instruction::Export(self.u16()?),
+            47 => Instruction::Import(self.u16()?),
+            48 => Instruction::DefineConstGlobal(self.u16()?),
+            _ => return self.error("Unknown opcode."),
+        };
+        Ok(instruction)
+    }
+}
+
+/// Checks that running a function can't make the VM read outside of its
+/// stack, constants or upvalues. Every path through the code is followed,
+/// tracking the height of the stack of the frame, which must be the same
+/// wherever paths meet.
+fn verify(function: &Function, gc: &Gc) -> Result<(), &'static str> {
+    let chunk = &function.chunk;
+    let mut heights = vec![None; chunk.code.len()];
+    let mut pending = Vec::new();
+    // The callee and its arguments.
+    reach(&mut heights, &mut pending, 0, function.arity + 1)?;
+    for handler in &chunk.handlers {
+        // The stack is unwound to the handler's depth and the exception pushed.
+        reach(
+            &mut heights,
+            &mut pending,
+            handler.target,
+            handler.depth + 1,
+        )?;
+    }
+
+    let mut max_height = function.arity + 1;
+    while let Some(offset) = pending.pop() {
+        let height = heights[offset].unwrap();
+        let instruction = chunk.code[offset];
+        check_operands(function, gc, instruction, height)?;
+        let (pops, pushes) = stack_effect(instruction);
+        if pops > height {
+            return Err("Stack underflow.");
+        }
+        let unwinds_above = chunk.handlers.iter().any(|handler| {
+            handler.start <= offset && offset < handler.end && handler.depth > height - pops
+        });
+        if unwinds_above {
+            return Err("Exception handler out of range.");
+        }
+        let height = height - pops + pushes;
+        max_height = max_height.max(height);
+
+        let ends_path = matches!(
+            instruction,
+            Instruction::Jump(_) | Instruction::Loop(_) | Instruction::Return | Instruction::Throw
+        );
+        if !ends_path && offset + 1 == chunk.code.len() {
+            return Err("Code runs past the end.");
+        }
+        let next = match instruction {
+            Instruction::Return | Instruction::Throw => continue,
+            Instruction::Jump(jump) => offset + 1 + jump as usize,
+            Instruction::Loop(jump) => match offset.checked_sub(jump as usize) {
+                Some(target) => target,
+                None => return Err("Jump out of range."),
+            },
+            Instruction::JumpIfFalse(jump) => {
+                reach(
+                    &mut heights,
+                    &mut pending,
+                    offset + 1 + jump as usize,
+                    height,
+                )?;
+                offset + 1
+            }
+            _ => offset + 1,
+        };
+        reach(&mut heights, &mut pending, next, height)?;
+    }
+
+    // The debugger reads local variables from the frame.
+    if function.locals.iter().any(|local| local.slot >= max_height) {
+        return Err("Local variable out of range.");
+    }
+    Ok(())
+}
+
+/// Records the stack height at `offset`, queueing the instruction there if
+/// it hasn't been reached before.
+fn reach(
+    heights: &mut [Option<usize>],
+    pending: &mut Vec<usize>,
+    offset: usize,
+    height: usize,
+) -> Result<(), &'static str> {
+    match heights.get(offset) {
+        None => Err("Jump out of range."),
+        Some(None) => {
+            heights[offset] = Some(height);
+            pending.push(offset);
+            Ok(())
+        }
+        Some(&Some(previous)) if previous == height => Ok(()),
+        Some(Some(_)) => Err("Inconsistent stack height."),
+    }
+}
+
+fn check_operands(
+    function: &Function,
+    gc: &Gc,
+    instruction: Instruction,
+    height: usize,
+) -> Result<(), &'static str> {
+    let constant = |index: u16| match function.chunk.constants.get(index as usize) {
+        Some(&value) => Ok(value),
+        None => Err("Constant index out of range."),
+    };
+    match instruction {
+        Instruction::Class(c)
+        | Instruction::DefineConstGlobal(c)
+        | Instruction::DefineGlobal(c)
+        | Instruction::Export(c)
+        | Instruction::GetGlobal(c)
+        | Instruction::GetProperty(c)
+        | Instruction::GetSuper(c)
+        | Instruction::Import(c)
+        | Instruction::Invoke((c, _))
+        | Instruction::Method(c)
+        | Instruction::SetGlobal(c)
+        | Instruction::SetProperty(c)
+        | Instruction::SuperInvoke((c, _)) => match constant(c)? {
+            Value::String(_) => Ok(()),
+            _ => Err("Constant is not a string."),
+        },
+        Instruction::Constant(c) => constant(c).map(|_| ()),
+        Instruction::Closure(c) => match constant(c)? {
+            Value::Function(closure) => {
+                for upvalue in &gc.deref(closure).upvalues {
+                    // Captured from the stack of this frame, where the new
+                    // closure itself is about to be pushed, or from the
+                    // upvalues of this closure.
+                    let count = if upvalue.is_local {
+                        height + 1
+                    } else {
+                        function.upvalues.len()
+                    };
+                    if upvalue.index as usize >= count {
+                        return Err("Upvalue index out of range.");
+                    }
+                }
+                Ok(())
+            }
+            _ => Err("Constant is not a function."),
+        },
+        Instruction::GetLocal(slot) | Instruction::SetLocal(slot) if slot as usize >= height => {
+            Err("Local slot out of range.")
+        }
+        Instruction::GetUpvalue(index) | Instruction::SetUpvalue(index)
+            if index as usize >= function.upvalues.len() =>
+        {
+            Err("Upvalue index out of range.")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Number of values an instruction pops from the stack, or reads below the
+/// top, and number of values it then pushes.
+fn stack_effect(instruction: Instruction) -> (usize, usize) {
+    match instruction {
+        Instruction::Add
+        | Instruction::Divide
+        | Instruction::Equal
+        | Instruction::FloorDivide
+        | Instruction::GetIndex
+        | Instruction::GetSuper(_)
+        | Instruction::Greater
+        | Instruction::Inherit
+        | Instruction::Less
+        | Instruction::Method(_)
+        | Instruction::Modulo
+        | Instruction::Multiply
+        | Instruction::Power
+        | Instruction::SetProperty(_)
+        | Instruction::Substract => (2, 1),
+        Instruction::BuildList(count) | Instruction::BuildString(count) => (count as usize, 1),
+        Instruction::BuildMap(count) => (2 * count as usize, 1),
+        Instruction::Call(args) | Instruction::Invoke((_, args)) => (args as usize + 1, 1),
+        // The superclass is above the receiver and the arguments.
+        Instruction::SuperInvoke((_, args)) => (args as usize + 2, 1),
+        Instruction::Class(_)
+        | Instruction::Closure(_)
+        | Instruction::Constant(_)
+        | Instruction::False
+        | Instruction::GetGlobal(_)
+        | Instruction::GetLocal(_)
+        | Instruction::GetUpvalue(_)
+        | Instruction::Import(_)
+        | Instruction::Nil
+        | Instruction::True => (0, 1),
+        Instruction::CloseUpvalue
+        | Instruction::DefineConstGlobal(_)
+        | Instruction::DefineGlobal(_)
+        | Instruction::Pop
+        | Instruction::Print
+        | Instruction::Return
+        | Instruction::Throw => (1, 0),
+        Instruction::Export(_) | Instruction::Jump(_) | Instruction::Loop(_) => (0, 0),
+        Instruction::GetProperty(_)
+        | Instruction::JumpIfFalse(_)
+        | Instruction::Negate
+        | Instruction::Not
+        | Instruction::SetGlobal(_)
+        | Instruction::SetLocal(_)
+        | Instruction::SetUpvalue(_) => (1, 1),
+        Instruction::SetIndex => (3, 1),
+    }
+}
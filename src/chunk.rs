@@ -1,6 +1,7 @@
 use crate::{
-    gc::{Gc, GcRef, GcTrace},
-    objects::{BoundMethod, Class, Closure, Function, Instance, NativeFunction},
+    gc::{Gc, GcRef, GcTrace, GcTraceFormatter},
+    objects::{BoundMethod, Class, Closure, Function, Instance, List, Map, Module, NativeFunction},
+    scanner::Span,
 };
 use std::{any::Any, collections::HashMap, fmt};
 
@@ -12,7 +13,10 @@ pub enum Value {
     Closure(GcRef<Closure>),
     Function(GcRef<Function>),
     Instance(GcRef<Instance>),
-    NativeFunction(NativeFunction),
+    List(GcRef<List>),
+    Map(GcRef<Map>),
+    Module(GcRef<Module>),
+    NativeFunction(GcRef<NativeFunction>),
     Nil,
     Number(f64),
     String(GcRef<String>),
@@ -37,7 +41,10 @@ impl GcTrace for Value {
             Value::Closure(value) => gc.deref(*value).format(f, gc),
             Value::Function(value) => gc.deref(*value).format(f, gc),
             Value::Instance(value) => gc.deref(*value).format(f, gc),
-            Value::NativeFunction(_) => write!(f, "<native fn>"),
+            Value::List(value) => gc.deref(*value).format(f, gc),
+            Value::Map(value) => gc.deref(*value).format(f, gc),
+            Value::Module(value) => gc.deref(*value).format(f, gc),
+            Value::NativeFunction(value) => gc.deref(*value).format(f, gc),
             Value::Nil => write!(f, "nil"),
             Value::Number(value) => write!(f, "{}", value),
             Value::String(value) => gc.deref(*value).format(f, gc),
@@ -53,6 +60,10 @@ impl GcTrace for Value {
             Value::Closure(value) => gc.mark_object(*value),
             Value::Function(value) => gc.mark_object(*value),
             Value::Instance(value) => gc.mark_object(*value),
+            Value::List(value) => gc.mark_object(*value),
+            Value::Map(value) => gc.mark_object(*value),
+            Value::Module(value) => gc.mark_object(*value),
+            Value::NativeFunction(value) => gc.mark_object(*value),
             Value::String(value) => gc.mark_object(*value),
             _ => (),
         }
@@ -65,54 +76,194 @@ impl GcTrace for Value {
     }
 }
 
+/// Conversion of a Lox value into a host Rust type.
+pub trait FromValue: Sized {
+    fn from_value(value: Value, gc: &Gc) -> Option<Self>;
+}
+
+/// Conversion of a host Rust type into a Lox value.
+pub trait IntoValue {
+    fn into_value(self, gc: &mut Gc) -> Value;
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value, _gc: &Gc) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value, _gc: &Gc) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value, _gc: &Gc) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value, gc: &Gc) -> Option<Self> {
+        match value {
+            Value::String(s) => Some(gc.deref(s).clone()),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _gc: &mut Gc) -> Value {
+        self
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _gc: &mut Gc) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _gc: &mut Gc) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _gc: &mut Gc) -> Value {
+        Value::Nil
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, gc: &mut Gc) -> Value {
+        Value::String(gc.intern(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, gc: &mut Gc) -> Value {
+        Value::String(gc.intern(self.to_owned()))
+    }
+}
+
 pub type Table = HashMap<GcRef<String>, Value>;
 
+/// A value that can be used as a key of a map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Bool(bool),
+    Nil,
+    Number(u64),
+    String(GcRef<String>),
+}
+
+impl MapKey {
+    pub fn from_value(value: Value) -> Option<MapKey> {
+        match value {
+            Value::Bool(b) => Some(MapKey::Bool(b)),
+            Value::Nil => Some(MapKey::Nil),
+            // Adding zero turns -0 into 0, so that both find the same entry.
+            Value::Number(n) => Some(MapKey::Number((n + 0.0).to_bits())),
+            Value::String(s) => Some(MapKey::String(s)),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(self) -> Value {
+        match self {
+            MapKey::Bool(b) => Value::Bool(b),
+            MapKey::Nil => Value::Nil,
+            MapKey::Number(bits) => Value::Number(f64::from_bits(bits)),
+            MapKey::String(s) => Value::String(s),
+        }
+    }
+}
+
+pub type ValueTable = HashMap<MapKey, Value>;
+
 #[derive(Debug, Copy, Clone)]
 pub enum Instruction {
     Add,
-    Call(u8),
-    Class(u8),
+    BuildList(u16),
+    BuildMap(u16),
+    BuildString(u16),
+    Call(u16),
+    Class(u16),
     CloseUpvalue,
-    Closure(u8),
-    Constant(u8),
-    DefineGlobal(u8),
+    Closure(u16),
+    Constant(u16),
+    DefineConstGlobal(u16),
+    DefineGlobal(u16),
     Divide,
     Equal,
+    Export(u16),
     False,
-    GetGlobal(u8),
-    GetLocal(u8),
-    GetProperty(u8),
-    GetSuper(u8),
-    GetUpvalue(u8),
+    FloorDivide,
+    GetGlobal(u16),
+    GetIndex,
+    GetLocal(u16),
+    GetProperty(u16),
+    GetSuper(u16),
+    GetUpvalue(u16),
     Greater,
+    Import(u16),
     Inherit,
-    Invoke((u8, u8)),
+    Invoke((u16, u16)),
     Jump(u16),
     JumpIfFalse(u16),
     Less,
     Loop(u16),
-    Method(u8),
+    Method(u16),
+    Modulo,
     Multiply,
     Negate,
     Nil,
     Not,
     Pop,
+    Power,
     Print,
     Return,
-    SetGlobal(u8),
-    SetLocal(u8),
-    SetProperty(u8),
-    SetUpvalue(u8),
+    SetGlobal(u16),
+    SetIndex,
+    SetLocal(u16),
+    SetProperty(u16),
+    SetUpvalue(u16),
     Substract,
-    SuperInvoke((u8, u8)),
+    SuperInvoke((u16, u16)),
+    Throw,
     True,
 }
 
+/// A range of code protected by a `catch` or `finally` clause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handler {
+    /// Offsets of the first instruction covered and the one after the last.
+    pub start: usize,
+    pub end: usize,
+    /// Offset of the code that runs when an exception is thrown in the range.
+    pub target: usize,
+    /// Stack slots of the frame still in use when the handler runs. The
+    /// exception is pushed right above them.
+    pub depth: usize,
+}
+
 #[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<Instruction>,
     pub constants: Vec<Value>,
     pub lines: Vec<usize>,
+    pub spans: Vec<Span>,
+    /// Exception handlers, innermost first.
+    pub handlers: Vec<Handler>,
 }
 
 impl Chunk {
@@ -121,11 +272,14 @@ impl Chunk {
             code: Vec::new(),
             constants: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
+            handlers: Vec::new(),
         }
     }
-    pub fn write(&mut self, instruction: Instruction, line: usize) -> usize {
+    pub fn write(&mut self, instruction: Instruction, line: usize, span: Span) -> usize {
         self.code.push(instruction);
         self.lines.push(line);
+        self.spans.push(span);
         self.code.len() - 1
     }
 
@@ -134,11 +288,19 @@ impl Chunk {
         self.constants.len() - 1
     }
 
-    pub fn read_constant(&self, index: u8) -> Value {
+    /// Finds the innermost handler that covers the instruction at `offset`.
+    pub fn find_handler(&self, offset: usize) -> Option<Handler> {
+        self.handlers
+            .iter()
+            .find(|handler| handler.start <= offset && offset < handler.end)
+            .copied()
+    }
+
+    pub fn read_constant(&self, index: u16) -> Value {
         self.constants[index as usize]
     }
 
-    pub fn read_string(&self, index: u8) -> GcRef<String> {
+    pub fn read_string(&self, index: u16) -> GcRef<String> {
         if let Value::String(s) = self.read_constant(index) {
             s
         } else {
@@ -147,116 +309,172 @@ impl Chunk {
     }
 }
 
-#[cfg(feature = "debug_trace_execution")]
 pub struct Disassembler<'vm> {
     gc: &'vm Gc,
     chunk: &'vm Chunk,
     stack: Option<&'vm Vec<Value>>,
 }
 
-#[cfg(feature = "debug_trace_execution")]
 impl<'vm> Disassembler<'vm> {
     pub fn new(gc: &'vm Gc, chunk: &'vm Chunk, stack: Option<&'vm Vec<Value>>) -> Self {
         Disassembler { gc, chunk, stack }
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("== BEGIN {} ==", name);
+    pub fn disassemble(&self, name: &str) -> String {
+        let mut out = format!("== BEGIN {} ==\n", name);
         for (offset, instruction) in self.chunk.code.iter().enumerate() {
-            self.instruction(instruction, offset);
+            out.push_str(&self.instruction(instruction, offset));
+        }
+        for handler in &self.chunk.handlers {
+            out.push_str(&format!(
+                "handler {:04}-{:04} -> {:04} depth {}\n",
+                handler.start, handler.end, handler.target, handler.depth
+            ));
         }
-        println!("== END {} ==", name);
-        println!();
+        out.push_str(&format!("== END {} ==\n\n", name));
+        out
     }
 
-    pub fn instruction(&self, instruction: &Instruction, offset: usize) {
-        self.stack();
-        print!("{:04} ", offset);
+    pub fn instruction(&self, instruction: &Instruction, offset: usize) -> String {
+        let mut out = self.stack();
+        out.push_str(&format!("{:04} ", offset));
         let line = self.chunk.lines[offset];
         if offset > 0 && line == self.chunk.lines[offset - 1] {
-            print!("   | ");
+            out.push_str("   | ");
         } else {
-            print!("{:>4} ", line);
+            out.push_str(&format!("{:>4} ", line));
         }
-        match instruction {
-            Instruction::Add => println!("OP_ADD"),
+        let text = match instruction {
+            Instruction::Add => "OP_ADD".to_owned(),
+            Instruction::BuildList(count) => format!("{:<16} {:4}", "OP_BUILD_LIST", *count),
+            Instruction::BuildMap(count) => format!("{:<16} {:4}", "OP_BUILD_MAP", *count),
+            Instruction::BuildString(count) => {
+                format!("{:<16} {:4}", "OP_BUILD_STRING", *count)
+            }
             Instruction::Class(c) => self.const_instruction("OP_CLASS", *c),
-            Instruction::CloseUpvalue => println!("OP_CLOSE_UPVALUE"),
+            Instruction::CloseUpvalue => "OP_CLOSE_UPVALUE".to_owned(),
             Instruction::Closure(c) => self.const_instruction("OP_CLOSURE", *c),
             Instruction::Constant(c) => self.const_instruction("OP_CONSTANT", *c),
-            Instruction::Call(args) => println!("{:<16} {:4}", "OP_CALL", *args),
+            Instruction::Call(args) => format!("{:<16} {:4}", "OP_CALL", *args),
+            Instruction::DefineConstGlobal(c) => {
+                self.const_instruction("OP_DEFINE_CONST_GLOBAL", *c)
+            }
             Instruction::DefineGlobal(c) => self.const_instruction("OP_DEFINE_GLOBAL", *c),
-            Instruction::Divide => println!("OP_DIVIDE"),
-            Instruction::Equal => println!("OP_EQUAL"),
-            Instruction::False => println!("OP_FALSE"),
+            Instruction::Divide => "OP_DIVIDE".to_owned(),
+            Instruction::Equal => "OP_EQUAL".to_owned(),
+            Instruction::Export(c) => self.const_instruction("OP_EXPORT", *c),
+            Instruction::False => "OP_FALSE".to_owned(),
+            Instruction::FloorDivide => "OP_FLOOR_DIVIDE".to_owned(),
             Instruction::GetGlobal(c) => self.const_instruction("OP_GET_GLOBAL", *c),
+            Instruction::GetIndex => "OP_GET_INDEX".to_owned(),
             Instruction::GetLocal(s) => self.slot_instruction("OP_GET_LOCAL", *s),
             Instruction::GetProperty(c) => self.const_instruction("OP_GET_PROPERTY", *c),
             Instruction::GetSuper(c) => self.const_instruction("OP_GET_SUPER", *c),
             Instruction::GetUpvalue(s) => self.slot_instruction("OP_GET_UPVALUE", *s),
-            Instruction::Greater => println!("OP_GREATER"),
+            Instruction::Greater => "OP_GREATER".to_owned(),
+            Instruction::Import(c) => self.const_instruction("OP_IMPORT", *c),
             Instruction::Invoke((c, args)) => self.invoke_instruction("OP_INVOKE", *c, *args),
-            Instruction::Inherit => println!("OP_INHERIT"),
-            Instruction::Jump(offset) => self.jump_instruction("OP_JUMP", *offset),
-            Instruction::JumpIfFalse(offset) => self.jump_instruction("OP_JUMP_IF_FALSE", *offset),
-            Instruction::Less => println!("OP_LESS"),
-            Instruction::Loop(offset) => self.jump_instruction("OP_LOOP", *offset),
+            Instruction::Inherit => "OP_INHERIT".to_owned(),
+            Instruction::Jump(jump) => {
+                self.jump_instruction("OP_JUMP", offset, offset + 1 + *jump as usize)
+            }
+            Instruction::JumpIfFalse(jump) => {
+                self.jump_instruction("OP_JUMP_IF_FALSE", offset, offset + 1 + *jump as usize)
+            }
+            Instruction::Less => "OP_LESS".to_owned(),
+            Instruction::Loop(jump) => {
+                self.jump_instruction("OP_LOOP", offset, offset - *jump as usize)
+            }
             Instruction::Method(c) => self.const_instruction("OP_METHOD", *c),
-            Instruction::Multiply => println!("OP_MULTIPLY"),
-            Instruction::Negate => println!("OP_NEGATE"),
-            Instruction::Not => println!("OP_NOT"),
-            Instruction::Nil => println!("OP_NIL"),
-            Instruction::Pop => println!("OP_POP"),
-            Instruction::Print => println!("OP_PRINT"),
-            Instruction::Return => println!("OP_RETURN"),
+            Instruction::Multiply => "OP_MULTIPLY".to_owned(),
+            Instruction::Modulo => "OP_MODULO".to_owned(),
+            Instruction::Negate => "OP_NEGATE".to_owned(),
+            Instruction::Not => "OP_NOT".to_owned(),
+            Instruction::Nil => "OP_NIL".to_owned(),
+            Instruction::Pop => "OP_POP".to_owned(),
+            Instruction::Power => "OP_POWER".to_owned(),
+            Instruction::Print => "OP_PRINT".to_owned(),
+            Instruction::Return => "OP_RETURN".to_owned(),
             Instruction::SetGlobal(c) => self.const_instruction("OP_SET_GLOBAL", *c),
+            Instruction::SetIndex => "OP_SET_INDEX".to_owned(),
             Instruction::SetLocal(s) => self.slot_instruction("OP_SET_LOCAL", *s),
             Instruction::SetProperty(c) => self.const_instruction("OP_SET_PROPERTY", *c),
             Instruction::SetUpvalue(s) => self.slot_instruction("OP_SET_UPVALUE", *s),
-            Instruction::Substract => println!("OP_SUBSTRACT"),
+            Instruction::Substract => "OP_SUBSTRACT".to_owned(),
             Instruction::SuperInvoke((c, args)) => {
                 self.invoke_instruction("OP_SUPER_INVOKE", *c, *args)
             }
-            Instruction::True => println!("OP_TRUE"),
+            Instruction::Throw => "OP_THROW".to_owned(),
+            Instruction::True => "OP_TRUE".to_owned(),
+        };
+        out.push_str(&text);
+        out.push('\n');
+        if let Instruction::Closure(c) = instruction {
+            if let Value::Function(function) = self.chunk.constants[*c as usize] {
+                for upvalue in &self.gc.deref(function).upvalues {
+                    let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                    out.push_str(&format!(
+                        "{:04}    |                     {} {}\n",
+                        offset, kind, upvalue.index
+                    ));
+                }
+            }
         }
+        out
     }
 
-    fn const_instruction(&self, instruction: &str, constant_index: u8) {
+    fn const_instruction(&self, instruction: &str, constant_index: u16) -> String {
         let value = self.chunk.constants[constant_index as usize];
-        println!(
+        format!(
             "{:<16} {:4} ({})",
             instruction,
             constant_index,
-            crate::gc::GcTraceFormatter::new(value, self.gc)
-        );
+            GcTraceFormatter::new(value, self.gc)
+        )
     }
 
-    fn slot_instruction(&self, instruction: &str, slot: u8) {
-        println!("{:<16} {:4}", instruction, slot);
+    fn slot_instruction(&self, instruction: &str, slot: u16) -> String {
+        format!("{:<16} {:4}", instruction, slot)
     }
 
-    fn jump_instruction(&self, instruction: &str, offset: u16) {
-        println!("{:<16} {:4}", instruction, offset);
+    fn jump_instruction(&self, instruction: &str, offset: usize, target: usize) -> String {
+        format!("{:<16} {:4} -> {}", instruction, offset, target)
     }
 
-    fn invoke_instruction(&self, instruction: &str, constant_index: u8, args: u8) {
+    fn invoke_instruction(&self, instruction: &str, constant_index: u16, args: u16) -> String {
         let value = self.chunk.constants[constant_index as usize];
-        println!(
+        format!(
             "{:<16} {:4} ({}) {}",
             instruction,
             constant_index,
-            crate::gc::GcTraceFormatter::new(value, self.gc),
+            GcTraceFormatter::new(value, self.gc),
             args
-        );
+        )
     }
 
-    fn stack(&self) {
+    fn stack(&self) -> String {
+        let mut out = String::new();
         if let Some(stack) = self.stack {
-            print!(" S: ");
+            out.push_str(" S: ");
             for &value in stack.iter() {
-                print!("[{}]", crate::gc::GcTraceFormatter::new(value, self.gc));
+                out.push_str(&format!("[{}]", GcTraceFormatter::new(value, self.gc)));
             }
-            println!();
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Disassembles a function followed by every function nested in its
+/// constants, depth first.
+pub fn disassemble_function(function: GcRef<Function>, gc: &Gc) -> String {
+    let name = GcTraceFormatter::new(Value::Function(function), gc).to_string();
+    let chunk = &gc.deref(function).chunk;
+    let mut out = Disassembler::new(gc, chunk, None).disassemble(&name);
+    for &constant in &chunk.constants {
+        if let Value::Function(nested) = constant {
+            out.push_str(&disassemble_function(nested, gc));
         }
     }
+    out
 }
@@ -1,10 +1,9 @@
 use crate::{
-    chunk::{Instruction, Value},
-    error::LoxError,
+    chunk::{Handler, Instruction, Value},
+    error::{Diagnostic, LoxError, Severity},
     gc::{Gc, GcRef},
-    objects::Function,
-    objects::FunctionUpvalue,
-    scanner::{Scanner, Token, TokenType},
+    objects::{Function, FunctionUpvalue, LocalVariable},
+    scanner::{string_value, Scanner, Span, Token, TokenType},
 };
 use std::collections::HashMap;
 use std::{convert::TryFrom, mem};
@@ -18,9 +17,10 @@ enum Precedence {
     Equality,   // == !=
     Comparison, // < > <= >=
     Term,       // + -
-    Factor,     // * /
+    Factor,     // * / % ~/
     Unary,      // ! -
-    Call,       // . ()
+    Exponent,   // **
+    Call,       // . () []
     Primary,
 }
 
@@ -35,7 +35,8 @@ impl Precedence {
             Precedence::Comparison => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
-            Precedence::Unary => Precedence::Call,
+            Precedence::Unary => Precedence::Exponent,
+            Precedence::Exponent => Precedence::Call,
             Precedence::Call => Precedence::Primary,
             Precedence::Primary => Precedence::None,
         }
@@ -70,6 +71,9 @@ struct Local<'sourcecode> {
     name: Token<'sourcecode>,
     depth: i32,
     is_captured: bool,
+    /// Index of the variable in the function's debug info, once initialized.
+    variable: Option<usize>,
+    constant: Option<Constant>,
 }
 
 impl<'sourcecode> Local<'sourcecode> {
@@ -78,10 +82,56 @@ impl<'sourcecode> Local<'sourcecode> {
             name,
             depth,
             is_captured: false,
+            variable: None,
+            constant: None,
         }
     }
 }
 
+/// A variable declared with `const`.
+#[derive(Copy, Clone)]
+struct Constant {
+    /// Value of the initializer if it's a number or string known at compile
+    /// time, which is used instead of reading the variable.
+    value: Option<Value>,
+}
+
+/// A loop being compiled, the target of `break` and `continue`.
+struct Loop {
+    /// Where `continue` jumps to: the condition, or the increment clause of a
+    /// `for` loop.
+    start: usize,
+    /// Scope depth outside of the loop body. Locals deeper than this are
+    /// discarded when jumping out of the body.
+    scope_depth: i32,
+    /// Jumps of the `break` statements, patched once the loop ends.
+    breaks: Vec<usize>,
+}
+
+/// A `try` statement being compiled. Statements that leave its body or its
+/// `catch` clause jump to the `finally` clause first, leaving what to do
+/// afterwards in two hidden locals: a pending value and its kind.
+struct Try {
+    value_slot: u16,
+    kind_slot: u16,
+    /// Scope depth of the hidden locals.
+    scope_depth: i32,
+    /// Number of loops enclosing the statement.
+    loop_count: usize,
+    /// Jumps to the `finally` clause, patched once it's reached.
+    exits: Vec<usize>,
+    has_return: bool,
+    /// `break` (true) and `continue` (false) statements that leave the body.
+    jumps: Vec<bool>,
+}
+
+impl Try {
+    // Kinds of pending values. Nil means that the body completed normally.
+    const THROW: f64 = 0.0;
+    const RETURN: f64 = 1.0;
+    const FIRST_JUMP: f64 = 2.0;
+}
+
 #[derive(Clone, Copy)]
 pub enum FunctionType {
     Function,
@@ -96,57 +146,95 @@ struct Compiler<'sourcecode> {
     function_type: FunctionType,
     locals: Vec<Local<'sourcecode>>,
     scope_depth: i32,
+    expression_starts: Vec<usize>,
+    loops: Vec<Loop>,
+    tries: Vec<Try>,
 }
 
 impl<'sourcecode> Compiler<'sourcecode> {
-    const LOCAL_COUNT: usize = std::u8::MAX as usize + 1;
+    const LOCAL_COUNT: usize = u16::MAX as usize + 1;
+    const MAX_ARGUMENTS: usize = u16::MAX as usize;
+    const MAX_ELEMENTS: usize = u16::MAX as usize;
 
     fn new(function_name: GcRef<String>, kind: FunctionType) -> Box<Self> {
         let mut compiler = Compiler {
             enclosing: None,
             function: Function::new(function_name),
             function_type: kind,
-            locals: Vec::with_capacity(Compiler::LOCAL_COUNT),
+            locals: Vec::new(),
             scope_depth: 0,
+            expression_starts: Vec::new(),
+            loops: Vec::new(),
+            tries: Vec::new(),
         };
 
         let token = match kind {
             FunctionType::Method | FunctionType::Initializer => Token::synthetic("this"),
             _ => Token::synthetic(""),
         };
+        if !token.lexeme.is_empty() {
+            compiler.function.locals.push(LocalVariable {
+                name: token.lexeme.to_owned(),
+                slot: 0,
+                start: 0,
+                end: usize::MAX,
+            });
+        }
         compiler.locals.push(Local::new(token, 0));
         Box::new(compiler)
     }
 
-    fn resolve_local(&mut self, name: Token, errors: &mut Vec<&'static str>) -> Option<u8> {
+    /// Records the end of the scope of the local variable in `slot`.
+    fn end_local(&mut self, slot: usize) {
+        let end = self.function.chunk.code.len();
+        if let Some(variable) = self.locals[slot].variable {
+            self.function.locals[variable].end = end;
+        }
+    }
+
+    /// Ends the scope of all the local variables that are still open.
+    fn end_locals(&mut self) {
+        let end = self.function.chunk.code.len();
+        for local in self.function.locals.iter_mut() {
+            local.end = local.end.min(end);
+        }
+    }
+
+    fn resolve_local(&mut self, name: Token, errors: &mut Vec<&'static str>) -> Option<u16> {
         for (i, local) in self.locals.iter().enumerate().rev() {
             if name.lexeme == local.name.lexeme {
                 if local.depth == -1 {
                     errors.push("Can't read local variable in its own initializer.");
                 }
-                return Some(i as u8);
+                return Some(i as u16);
             }
         }
         None
     }
 
-    fn resolve_upvalue(&mut self, name: Token, errors: &mut Vec<&'static str>) -> Option<u8> {
+    fn resolve_upvalue(&mut self, name: Token, errors: &mut Vec<&'static str>) -> Option<u16> {
         if let Some(enclosing) = self.enclosing.as_mut() {
             if let Some(index) = enclosing.resolve_local(name, errors) {
                 enclosing.locals[index as usize].is_captured = true;
-                return Some(self.add_upvalue(index, true, errors));
+                return Some(self.add_upvalue(name, index, true, errors));
             }
             if let Some(index) = enclosing.resolve_upvalue(name, errors) {
-                return Some(self.add_upvalue(index, false, errors));
+                return Some(self.add_upvalue(name, index, false, errors));
             }
         }
         None
     }
 
-    fn add_upvalue(&mut self, index: u8, is_local: bool, errors: &mut Vec<&'static str>) -> u8 {
+    fn add_upvalue(
+        &mut self,
+        name: Token,
+        index: u16,
+        is_local: bool,
+        errors: &mut Vec<&'static str>,
+    ) -> u16 {
         for (i, upvalue) in self.function.upvalues.iter().enumerate() {
             if upvalue.index == index && upvalue.is_local == is_local {
-                return i as u8;
+                return i as u16;
             }
         }
         let count = self.function.upvalues.len();
@@ -158,7 +246,22 @@ impl<'sourcecode> Compiler<'sourcecode> {
 
         let upvalue = FunctionUpvalue { index, is_local };
         self.function.upvalues.push(upvalue);
-        count as u8
+        self.function.upvalue_names.push(name.lexeme.to_owned());
+        count as u16
+    }
+
+    /// Finds the local that `name` refers to, in this function or in the
+    /// enclosing ones.
+    fn find_local(&self, name: Token) -> Option<&Local<'sourcecode>> {
+        match self
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.name.lexeme == name.lexeme)
+        {
+            Some(local) => Some(local),
+            None => self.enclosing.as_ref()?.find_local(name),
+        }
     }
 
     fn is_local_declared(&self, name: Token) -> bool {
@@ -195,13 +298,18 @@ struct Parser<'sourcecode> {
     gc: &'sourcecode mut Gc,
     current: Token<'sourcecode>,
     previous: Token<'sourcecode>,
-    had_error: bool,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
     resolver_errors: Vec<&'static str>,
     rules: HashMap<TokenType, ParseRule<'sourcecode>>,
+    /// Global variables declared with `const` in this compilation unit.
+    global_constants: HashMap<&'sourcecode str, Constant>,
 }
 
 impl<'sourcecode> Parser<'sourcecode> {
+    /// Name given to function expressions, which don't have one.
+    const ANONYMOUS: &'static str = "anonymous";
+
     fn new(code: &'sourcecode str, gc: &'sourcecode mut Gc) -> Parser<'sourcecode> {
         let mut rules = HashMap::new();
 
@@ -218,8 +326,16 @@ impl<'sourcecode> Parser<'sourcecode> {
             P::Call,
         );
         rule(RightParen, None, None, P::None);
-        rule(LeftBrace, None, None, P::None);
+        rule(LeftBrace, Some(Parser::map), None, P::None);
         rule(RightBrace, None, None, P::None);
+        rule(
+            LeftBracket,
+            Some(Parser::list),
+            Some(Parser::subscript),
+            P::Call,
+        );
+        rule(RightBracket, None, None, P::None);
+        rule(Colon, None, None, P::None);
         rule(Comma, None, None, P::None);
         rule(Dot, None, Some(Parser::dot), P::Call);
         rule(Minus, Some(Parser::unary), Some(Parser::binary), P::Term);
@@ -227,37 +343,51 @@ impl<'sourcecode> Parser<'sourcecode> {
         rule(Semicolon, None, None, P::None);
         rule(Slash, None, Some(Parser::binary), P::Factor);
         rule(Star, None, Some(Parser::binary), P::Factor);
+        rule(Percent, None, Some(Parser::binary), P::Factor);
         rule(Bang, Some(Parser::unary), None, P::None);
         rule(BangEqual, None, Some(Parser::binary), P::Equality);
         rule(Equal, None, None, P::None);
         rule(EqualEqual, None, Some(Parser::binary), P::Equality);
+        rule(EqualGreater, None, None, P::None);
         rule(Greater, None, Some(Parser::binary), P::Comparison);
         rule(GreaterEqual, None, Some(Parser::binary), P::Comparison);
         rule(Less, None, Some(Parser::binary), P::Comparison);
         rule(LessEqual, None, Some(Parser::binary), P::Comparison);
+        rule(StarStar, None, Some(Parser::binary), P::Exponent);
+        rule(TildeSlash, None, Some(Parser::binary), P::Factor);
         rule(Identifier, Some(Parser::variable), None, P::None);
         rule(String, Some(Parser::string), None, P::None);
+        rule(Interpolation, Some(Parser::interpolation), None, P::None);
         rule(Number, Some(Parser::number), None, P::None);
         rule(And, None, Some(Parser::and_op), P::And);
+        rule(Break, None, None, P::None);
+        rule(Catch, None, None, P::None);
         rule(Class, None, None, P::None);
+        rule(Const, None, None, P::None);
+        rule(Continue, None, None, P::None);
         rule(Else, None, None, P::None);
+        rule(Export, None, None, P::None);
         rule(False, Some(Parser::literal), None, P::None);
+        rule(Finally, None, None, P::None);
         rule(For, None, None, P::None);
-        rule(Fun, None, None, P::None);
+        rule(Fun, Some(Parser::lambda), None, P::None);
         rule(If, None, None, P::None);
+        rule(Import, None, None, P::None);
         rule(Nil, Some(Parser::literal), None, P::None);
         rule(Or, None, Some(Parser::or_op), P::Or);
         rule(Print, None, None, P::None);
         rule(Return, None, None, P::None);
         rule(Super, Some(Parser::super_), None, P::None);
         rule(This, Some(Parser::this), None, P::None);
+        rule(Throw, None, None, P::None);
         rule(True, Some(Parser::literal), None, P::None);
+        rule(Try, None, None, P::None);
         rule(Var, None, None, P::None);
         rule(While, None, None, P::None);
         rule(Error, None, None, P::None);
         rule(Eof, None, None, P::None);
 
-        let function_name = gc.intern("script".to_owned());
+        let function_name = gc.intern("".to_owned());
 
         Parser {
             scanner: Scanner::new(code),
@@ -266,9 +396,10 @@ impl<'sourcecode> Parser<'sourcecode> {
             gc,
             current: Token::synthetic(""),
             previous: Token::synthetic(""),
-            had_error: false,
+            diagnostics: Vec::new(),
             panic_mode: false,
             resolver_errors: Vec::new(),
+            global_constants: HashMap::new(),
             rules,
         }
     }
@@ -281,15 +412,16 @@ impl<'sourcecode> Parser<'sourcecode> {
         }
 
         self.emit_return();
+        self.compiler.end_locals();
 
         #[cfg(feature = "debug_trace_execution")]
-        if !self.had_error {
+        if self.diagnostics.is_empty() {
             let dis = crate::chunk::Disassembler::new(self.gc, &self.compiler.function.chunk, None);
-            dis.disassemble("code");
+            print!("{}", dis.disassemble("code"));
         }
 
-        if self.had_error {
-            Err(LoxError::CompileError)
+        if !self.diagnostics.is_empty() {
+            Err(LoxError::CompileError(self.diagnostics))
         } else {
             Ok(self.gc.alloc(self.compiler.function))
         }
@@ -312,6 +444,12 @@ impl<'sourcecode> Parser<'sourcecode> {
             self.fun_declaration();
         } else if self.matches(TokenType::Var) {
             self.var_declaration();
+        } else if self.matches(TokenType::Const) {
+            self.const_declaration();
+        } else if self.matches(TokenType::Import) {
+            self.import_declaration();
+        } else if self.matches(TokenType::Export) {
+            self.export_declaration();
         } else {
             self.statement();
         }
@@ -364,6 +502,100 @@ impl<'sourcecode> Parser<'sourcecode> {
         }
     }
 
+    fn import_declaration(&mut self) {
+        if self.matches(TokenType::LeftBrace) {
+            let mut names = Vec::new();
+            if !self.check(TokenType::RightBrace) {
+                loop {
+                    self.consume(TokenType::Identifier, "Expect imported name.");
+                    let name = self.previous;
+                    let mut alias = name;
+                    if self.matches_word("as") {
+                        self.consume(TokenType::Identifier, "Expect name after 'as'.");
+                        alias = self.previous;
+                    }
+                    names.push((name, alias));
+                    if !self.matches(TokenType::Comma) {
+                        break;
+                    }
+                }
+            }
+            self.consume(TokenType::RightBrace, "Expect '}' after imported names.");
+            if !self.matches_word("from") {
+                self.error_at_current("Expect 'from' after imported names.");
+            }
+            let path = self.module_path();
+            self.consume(TokenType::Semicolon, "Expect ';' after import.");
+            for (name, alias) in names {
+                self.emit(Instruction::Import(path));
+                let name = self.identifier_constant(name);
+                self.emit(Instruction::GetProperty(name));
+                self.define_import(alias);
+            }
+        } else {
+            let path = self.module_path();
+            if !self.matches_word("as") {
+                self.error_at_current("Expect 'as' after module path.");
+            }
+            self.consume(TokenType::Identifier, "Expect module name.");
+            let name = self.previous;
+            self.consume(TokenType::Semicolon, "Expect ';' after import.");
+            self.emit(Instruction::Import(path));
+            self.define_import(name);
+        }
+    }
+
+    fn module_path(&mut self) -> u16 {
+        self.consume(TokenType::String, "Expect module path.");
+        let path = string_value(self.previous.lexeme);
+        let path = self.gc.intern(path);
+        self.make_constant(Value::String(path))
+    }
+
+    /// Defines a variable holding the imported value on top of the stack.
+    fn define_import(&mut self, name: Token<'sourcecode>) {
+        if self.compiler.scope_depth > 0 {
+            if self.compiler.is_local_declared(name) {
+                self.error_at(name, "Already variable with this name in this scope.");
+            }
+            self.add_local(name);
+            self.mark_initialized();
+        } else {
+            let name = self.identifier_constant(name);
+            self.emit(Instruction::DefineGlobal(name));
+        }
+    }
+
+    fn export_declaration(&mut self) {
+        if self.compiler.scope_depth > 0 || self.compiler.enclosing.is_some() {
+            self.error("Can only export top-level declarations.");
+        }
+        let name = if self.matches(TokenType::Class) {
+            let name = self.current;
+            self.class_declaration();
+            name
+        } else if self.matches(TokenType::Fun) {
+            let name = self.current;
+            self.fun_declaration();
+            name
+        } else if self.matches(TokenType::Var) {
+            let name = self.current;
+            self.var_declaration();
+            name
+        } else if self.matches(TokenType::Const) {
+            let name = self.current;
+            self.const_declaration();
+            name
+        } else {
+            self.error_at_current("Expect declaration after 'export'.");
+            return;
+        };
+        if name.kind == TokenType::Identifier {
+            let name = self.identifier_constant(name);
+            self.emit(Instruction::Export(name));
+        }
+    }
+
     fn fun_declaration(&mut self) {
         let global = self.parse_variable("Expect function name.");
         self.mark_initialized();
@@ -371,8 +603,8 @@ impl<'sourcecode> Parser<'sourcecode> {
         self.define_variable(global);
     }
 
-    fn push_compiler(&mut self, kind: FunctionType) {
-        let function_name = self.gc.intern(self.previous.lexeme.to_owned());
+    fn push_compiler(&mut self, name: &str, kind: FunctionType) {
+        let function_name = self.gc.intern(name.to_owned());
         let new_compiler = Compiler::new(function_name, kind);
         let old_compiler = mem::replace(&mut self.compiler, new_compiler);
         self.compiler.enclosing = Some(old_compiler);
@@ -380,6 +612,7 @@ impl<'sourcecode> Parser<'sourcecode> {
 
     fn pop_compiler(&mut self) -> Function {
         self.emit_return();
+        self.compiler.end_locals();
         match self.compiler.enclosing.take() {
             Some(enclosing) => {
                 let compiler = mem::replace(&mut self.compiler, enclosing);
@@ -390,14 +623,22 @@ impl<'sourcecode> Parser<'sourcecode> {
     }
 
     fn function(&mut self, kind: FunctionType) {
-        self.push_compiler(kind);
+        self.push_compiler(self.previous.lexeme, kind);
         self.begin_scope();
         self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        self.parameters();
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+        self.end_function();
+    }
+
+    /// Compiles a parameter list up to its closing parenthesis.
+    fn parameters(&mut self) {
         if !self.check(TokenType::RightParen) {
             loop {
                 self.compiler.function.arity += 1;
-                if self.compiler.function.arity > 255 {
-                    self.error_at_current("Can't have more than 255 parameters.");
+                if self.compiler.function.arity > Compiler::MAX_ARGUMENTS {
+                    self.error_at_current("Can't have more than 65535 parameters.");
                 }
                 let param = self.parse_variable("Expect parameter name.");
                 self.define_variable(param);
@@ -407,8 +648,10 @@ impl<'sourcecode> Parser<'sourcecode> {
             }
         }
         self.consume(TokenType::RightParen, "Expect ')' after parameters.");
-        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
-        self.block();
+    }
+
+    /// Finishes the function being compiled and emits the closure for it.
+    fn end_function(&mut self) {
         let function = self.pop_compiler();
         let fn_id = self.gc.alloc(function);
 
@@ -442,7 +685,88 @@ impl<'sourcecode> Parser<'sourcecode> {
         self.define_variable(index);
     }
 
-    fn define_variable(&mut self, index: u8) {
+    fn const_declaration(&mut self) {
+        let index = self.parse_variable("Expect constant name.");
+        let name = self.previous;
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        let start = self.compiler.function.chunk.code.len();
+        self.expression();
+        let constant = Constant {
+            value: self.fold_constant(start),
+        };
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+        if self.compiler.scope_depth > 0 {
+            if let Some(local) = self.compiler.locals.last_mut() {
+                local.constant = Some(constant);
+            }
+            self.mark_initialized();
+        } else {
+            self.global_constants.insert(name.lexeme, constant);
+            self.emit(Instruction::DefineConstGlobal(index));
+        }
+    }
+
+    /// Evaluates the code emitted since `start` if it only does arithmetic on
+    /// number and string literals, replacing it with the result. Returns the
+    /// value if the code is, or has been folded into, a single constant.
+    fn fold_constant(&mut self, start: usize) -> Option<Value> {
+        let chunk = &self.compiler.function.chunk;
+        let mut stack = Vec::new();
+        for &instruction in &chunk.code[start..] {
+            let value = match instruction {
+                Instruction::Constant(index) => match chunk.constants[index as usize] {
+                    value @ (Value::Number(_) | Value::String(_)) => value,
+                    _ => return None,
+                },
+                Instruction::Negate => match stack.pop()? {
+                    Value::Number(a) => Value::Number(-a),
+                    _ => return None,
+                },
+                Instruction::Add => match (stack.pop()?, stack.pop()?) {
+                    (Value::Number(b), Value::Number(a)) => Value::Number(a + b),
+                    (Value::String(b), Value::String(a)) => {
+                        let result = format!("{}{}", self.gc.deref(a), self.gc.deref(b));
+                        Value::String(self.gc.intern(result))
+                    }
+                    _ => return None,
+                },
+                _ => {
+                    // Same as the VM does for numbers.
+                    let op: fn(f64, f64) -> f64 = match instruction {
+                        Instruction::Substract => |a, b| a - b,
+                        Instruction::Multiply => |a, b| a * b,
+                        Instruction::Divide => |a, b| a / b,
+                        Instruction::FloorDivide => |a, b| (a / b).floor(),
+                        Instruction::Modulo => |a, b| a - b * (a / b).floor(),
+                        Instruction::Power => f64::powf,
+                        _ => return None,
+                    };
+                    match (stack.pop()?, stack.pop()?) {
+                        (Value::Number(b), Value::Number(a)) => Value::Number(op(a, b)),
+                        _ => return None,
+                    }
+                }
+            };
+            stack.push(value);
+        }
+        let value = match stack[..] {
+            [value] => value,
+            _ => return None,
+        };
+        if chunk.code.len() - start > 1 {
+            let chunk = &mut self.compiler.function.chunk;
+            chunk.code.truncate(start);
+            chunk.lines.truncate(start);
+            chunk.spans.truncate(start);
+            self.emit_constant(value);
+        }
+        Some(value)
+    }
+
+    fn define_variable(&mut self, index: u16) {
         if self.compiler.scope_depth > 0 {
             self.mark_initialized();
             return;
@@ -456,6 +780,16 @@ impl<'sourcecode> Parser<'sourcecode> {
         }
         let last_local = self.compiler.locals.last_mut().unwrap();
         last_local.depth = self.compiler.scope_depth;
+        let name = last_local.name.lexeme.to_owned();
+        last_local.variable = Some(self.compiler.function.locals.len());
+        let slot = self.compiler.locals.len() - 1;
+        let start = self.compiler.function.chunk.code.len();
+        self.compiler.function.locals.push(LocalVariable {
+            name,
+            slot,
+            start,
+            end: usize::MAX,
+        });
     }
 
     fn statement(&mut self) {
@@ -469,6 +803,14 @@ impl<'sourcecode> Parser<'sourcecode> {
             self.while_statement();
         } else if self.matches(TokenType::For) {
             self.for_statement();
+        } else if self.matches(TokenType::Break) {
+            self.break_statement();
+        } else if self.matches(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.matches(TokenType::Try) {
+            self.try_statement();
+        } else if self.matches(TokenType::Throw) {
+            self.throw_statement();
         } else if self.matches(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -490,7 +832,7 @@ impl<'sourcecode> Parser<'sourcecode> {
             }
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after return value.");
-            self.emit(Instruction::Return);
+            self.emit_return_value();
         }
     }
 
@@ -517,10 +859,12 @@ impl<'sourcecode> Parser<'sourcecode> {
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
         let exit_jump = self.emit(Instruction::JumpIfFalse(0xffff));
         self.emit(Instruction::Pop);
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
         self.patch_jump(exit_jump);
         self.emit(Instruction::Pop);
+        self.end_loop();
     }
 
     fn for_statement(&mut self) {
@@ -558,30 +902,287 @@ impl<'sourcecode> Parser<'sourcecode> {
             loop_start = increment_start;
             self.patch_jump(body_jump);
         }
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
         if let Option::Some(exit_jump) = exit_jump {
             self.patch_jump(exit_jump);
             self.emit(Instruction::Pop);
         }
+        self.end_loop();
         self.end_scope();
     }
 
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = self.compiler.scope_depth;
+        self.compiler.loops.push(Loop {
+            start,
+            scope_depth,
+            breaks: Vec::new(),
+        });
+    }
+
+    /// Patches the `break` jumps of the innermost loop to land here.
+    fn end_loop(&mut self) {
+        let current_loop = self.compiler.loops.pop().unwrap();
+        for jump in current_loop.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        if self.compiler.loops.is_empty() {
+            self.error("Can't use 'break' outside of a loop.");
+        } else {
+            self.emit_break();
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+    }
+
+    fn continue_statement(&mut self) {
+        if self.compiler.loops.is_empty() {
+            self.error("Can't use 'continue' outside of a loop.");
+        } else {
+            self.emit_continue();
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+    }
+
+    fn emit_break(&mut self) {
+        if self.leaves_try() {
+            self.emit_finally_jump(true);
+            return;
+        }
+        let depth = self.compiler.loops.last().unwrap().scope_depth;
+        self.discard_locals(depth);
+        let jump = self.emit(Instruction::Jump(0xffff));
+        self.compiler.loops.last_mut().unwrap().breaks.push(jump);
+    }
+
+    fn emit_continue(&mut self) {
+        if self.leaves_try() {
+            self.emit_finally_jump(false);
+            return;
+        }
+        let current_loop = self.compiler.loops.last().unwrap();
+        let (start, depth) = (current_loop.start, current_loop.scope_depth);
+        self.discard_locals(depth);
+        self.emit_loop(start);
+    }
+
+    /// Returns true if jumping out of the innermost loop leaves a `try`
+    /// statement.
+    fn leaves_try(&self) -> bool {
+        let compiler = &self.compiler;
+        compiler
+            .tries
+            .last()
+            .is_some_and(|current| current.loop_count == compiler.loops.len())
+    }
+
+    /// Jumps to the `finally` clause of the innermost `try` statement, which
+    /// runs the `break` or `continue` statement afterwards.
+    fn emit_finally_jump(&mut self, is_break: bool) {
+        let current = self.compiler.tries.last_mut().unwrap();
+        let kind = Try::FIRST_JUMP + current.jumps.len() as f64;
+        current.jumps.push(is_break);
+        self.emit_pending(kind);
+    }
+
+    /// Returns the value on top of the stack, running the `finally` clauses
+    /// of the enclosing `try` statements first.
+    fn emit_return_value(&mut self) {
+        match self.compiler.tries.last_mut() {
+            Some(current) => {
+                current.has_return = true;
+                let value_slot = current.value_slot;
+                self.emit(Instruction::SetLocal(value_slot));
+                self.emit(Instruction::Pop);
+                self.emit_pending(Try::RETURN);
+            }
+            None => {
+                self.emit(Instruction::Return);
+            }
+        }
+    }
+
+    /// Sets the kind of the pending value of the innermost `try` statement
+    /// and jumps to its `finally` clause.
+    fn emit_pending(&mut self, kind: f64) {
+        let current = self.compiler.tries.last().unwrap();
+        let (kind_slot, depth) = (current.kind_slot, current.scope_depth);
+        self.emit_constant(Value::Number(kind));
+        self.emit(Instruction::SetLocal(kind_slot));
+        self.emit(Instruction::Pop);
+        self.discard_locals(depth);
+        let jump = self.emit(Instruction::Jump(0xffff));
+        self.compiler.tries.last_mut().unwrap().exits.push(jump);
+    }
+
+    fn try_statement(&mut self) {
+        self.begin_scope();
+        let value_slot = self.add_hidden_local();
+        let kind_slot = self.add_hidden_local();
+        let depth = self.compiler.locals.len();
+        self.compiler.tries.push(Try {
+            value_slot,
+            kind_slot,
+            scope_depth: self.compiler.scope_depth,
+            loop_count: self.compiler.loops.len(),
+            exits: Vec::new(),
+            has_return: false,
+            jumps: Vec::new(),
+        });
+
+        let start = self.compiler.function.chunk.code.len();
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        let end = self.compiler.function.chunk.code.len();
+        let jump = self.emit(Instruction::Jump(0xffff));
+        self.compiler.tries.last_mut().unwrap().exits.push(jump);
+
+        let has_catch = self.matches(TokenType::Catch);
+        if has_catch {
+            // The exception is pushed in the slot of the catch variable.
+            let target = self.compiler.function.chunk.code.len();
+            self.add_handler(start, end, target, depth);
+            self.begin_scope();
+            self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+            self.parse_variable("Expect exception variable name.");
+            self.mark_initialized();
+            self.consume(
+                TokenType::RightParen,
+                "Expect ')' after exception variable.",
+            );
+            self.consume(TokenType::LeftBrace, "Expect '{' before catch body.");
+            self.block();
+            self.end_scope();
+            let jump = self.emit(Instruction::Jump(0xffff));
+            self.compiler.tries.last_mut().unwrap().exits.push(jump);
+        }
+        let has_finally = self.matches(TokenType::Finally);
+        if !has_catch && !has_finally {
+            self.error_at_current("Expect 'catch' or 'finally' after try block.");
+        }
+
+        let current = self.compiler.tries.pop().unwrap();
+        if has_finally {
+            // Exceptions thrown in the body or in the catch clause are kept
+            // pending while the finally clause runs.
+            let target = self.compiler.function.chunk.code.len();
+            self.add_handler(start, target, target, depth);
+            self.emit(Instruction::SetLocal(value_slot));
+            self.emit(Instruction::Pop);
+            self.emit_constant(Value::Number(Try::THROW));
+            self.emit(Instruction::SetLocal(kind_slot));
+            self.emit(Instruction::Pop);
+        }
+        for exit in current.exits {
+            self.patch_jump(exit);
+        }
+        if has_finally {
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+            self.resume_pending(kind_slot, Try::THROW, |parser| {
+                parser.emit(Instruction::GetLocal(value_slot));
+                parser.emit(Instruction::Throw);
+            });
+        }
+        if current.has_return {
+            self.resume_pending(kind_slot, Try::RETURN, |parser| {
+                parser.emit(Instruction::GetLocal(value_slot));
+                parser.emit_return_value();
+            });
+        }
+        for (i, is_break) in current.jumps.into_iter().enumerate() {
+            let kind = Try::FIRST_JUMP + i as f64;
+            self.resume_pending(kind_slot, kind, |parser| {
+                if is_break {
+                    parser.emit_break();
+                } else {
+                    parser.emit_continue();
+                }
+            });
+        }
+        self.end_scope();
+    }
+
+    /// Runs `resume` if the pending kind of a `try` statement is `kind`.
+    fn resume_pending(&mut self, kind_slot: u16, kind: f64, resume: impl FnOnce(&mut Self)) {
+        self.emit(Instruction::GetLocal(kind_slot));
+        self.emit_constant(Value::Number(kind));
+        self.emit(Instruction::Equal);
+        let skip = self.emit(Instruction::JumpIfFalse(0xffff));
+        self.emit(Instruction::Pop);
+        resume(self);
+        self.patch_jump(skip);
+        self.emit(Instruction::Pop);
+    }
+
+    /// Adds an unnamed local, initialized to nil, that user code can't refer
+    /// to.
+    fn add_hidden_local(&mut self) -> u16 {
+        self.emit(Instruction::Nil);
+        self.add_local(Token::synthetic(""));
+        let local = self.compiler.locals.last_mut().unwrap();
+        local.depth = self.compiler.scope_depth;
+        (self.compiler.locals.len() - 1) as u16
+    }
+
+    fn add_handler(&mut self, start: usize, end: usize, target: usize, depth: usize) {
+        self.compiler.function.chunk.handlers.push(Handler {
+            start,
+            end,
+            target,
+            depth,
+        });
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit(Instruction::Throw);
+    }
+
+    /// Emits the instructions to discard the locals deeper than `depth`
+    /// before jumping out of their scope. Unlike `end_scope`, the locals are
+    /// kept, as the code that follows the jump is still in their scope.
+    fn discard_locals(&mut self, depth: i32) {
+        for i in (0..self.compiler.locals.len()).rev() {
+            let local = self.compiler.locals[i];
+            if local.depth <= depth {
+                break;
+            }
+            if local.is_captured {
+                self.emit(Instruction::CloseUpvalue);
+            } else {
+                self.emit(Instruction::Pop);
+            }
+        }
+    }
+
     fn begin_scope(&mut self) {
         self.compiler.scope_depth += 1;
     }
 
     fn end_scope(&mut self) {
         self.compiler.scope_depth -= 1;
-        for i in (0..self.compiler.locals.len()).rev() {
-            if self.compiler.locals[i].depth > self.compiler.scope_depth {
-                if self.compiler.locals[i].is_captured {
-                    self.emit(Instruction::CloseUpvalue);
-                } else {
-                    self.emit(Instruction::Pop);
-                }
-                self.compiler.locals.pop();
+        while let Some(local) = self.compiler.locals.last() {
+            if local.depth <= self.compiler.scope_depth {
+                break;
+            }
+            let captured = local.is_captured;
+            self.compiler.end_local(self.compiler.locals.len() - 1);
+            if captured {
+                self.emit(Instruction::CloseUpvalue);
+            } else {
+                self.emit(Instruction::Pop);
             }
+            self.compiler.locals.pop();
         }
     }
 
@@ -608,10 +1209,48 @@ impl<'sourcecode> Parser<'sourcecode> {
     }
 
     fn string(&mut self, _can_assing: bool) {
-        let lexeme = self.previous.lexeme;
-        let value = &lexeme[1..(lexeme.len() - 1)];
-        let s = self.gc.intern(value.to_owned());
+        let value = string_value(self.previous.lexeme);
+        let s = self.gc.intern(value);
+        self.emit_constant(Value::String(s));
+    }
+
+    /// Compiles the parts of an interpolated string and the expressions
+    /// between them, which are turned into strings and concatenated.
+    fn interpolation(&mut self, _can_assign: bool) {
+        let mut count: usize = 0;
+        loop {
+            count += self.string_part();
+            if self.current.lexeme.starts_with('}') {
+                // The interpolation was closed right after being opened.
+                self.error_at_current("Expect expression.");
+            } else {
+                self.expression();
+                count += 1;
+            }
+            if !self.matches(TokenType::Interpolation) {
+                break;
+            }
+        }
+        self.consume(TokenType::String, "Expect end of string interpolation.");
+        if self.previous.kind == TokenType::String {
+            count += self.string_part();
+        }
+        if count > Compiler::MAX_ELEMENTS {
+            self.error("Too many interpolated expressions in string.");
+        }
+        self.emit(Instruction::BuildString(count as u16));
+    }
+
+    /// Pushes a part of an interpolated string unless it's empty, returning
+    /// the number of values pushed.
+    fn string_part(&mut self) -> usize {
+        let value = string_value(self.previous.lexeme);
+        if value.is_empty() {
+            return 0;
+        }
+        let s = self.gc.intern(value);
         self.emit_constant(Value::String(s));
+        1
     }
 
     fn literal(&mut self, _can_assing: bool) {
@@ -659,6 +1298,16 @@ impl<'sourcecode> Parser<'sourcecode> {
     }
 
     fn named_variable(&mut self, name: Token, can_assing: bool) {
+        let assign = can_assing && self.check(TokenType::Equal);
+        let constant = match self.compiler.find_local(name) {
+            Some(local) => local.constant,
+            None => self.global_constants.get(name.lexeme).copied(),
+        };
+        if let (false, Some(Constant { value: Some(value) })) = (assign, constant) {
+            self.emit_constant(value);
+            return;
+        }
+
         let get_op;
         let set_op;
         if let Some(arg) = self.resolve_local(name) {
@@ -673,7 +1322,11 @@ impl<'sourcecode> Parser<'sourcecode> {
             set_op = Instruction::SetGlobal(index);
         }
 
-        if can_assing && self.matches(TokenType::Equal) {
+        if assign {
+            self.advance();
+            if constant.is_some() {
+                self.error(&format!("Can't assign to constant '{}'.", name.lexeme));
+            }
             self.expression();
             self.emit(set_op);
         } else {
@@ -681,7 +1334,7 @@ impl<'sourcecode> Parser<'sourcecode> {
         }
     }
 
-    fn resolve_local(&mut self, name: Token) -> Option<u8> {
+    fn resolve_local(&mut self, name: Token) -> Option<u16> {
         let result = self.compiler.resolve_local(name, &mut self.resolver_errors);
         while let Some(e) = self.resolver_errors.pop() {
             self.error(e);
@@ -689,7 +1342,7 @@ impl<'sourcecode> Parser<'sourcecode> {
         result
     }
 
-    fn resolve_upvalue(&mut self, name: Token) -> Option<u8> {
+    fn resolve_upvalue(&mut self, name: Token) -> Option<u16> {
         let result = self
             .compiler
             .resolve_upvalue(name, &mut self.resolver_errors);
@@ -718,14 +1371,67 @@ impl<'sourcecode> Parser<'sourcecode> {
         }
     }
 
-    fn argument_list(&mut self) -> u8 {
+    fn list(&mut self, _can_assign: bool) {
+        let mut count: usize = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+
+                if count == Compiler::MAX_ELEMENTS {
+                    self.error("Can't have more than 65535 elements in a list literal.");
+                }
+
+                count += 1;
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.");
+        self.emit(Instruction::BuildList(count as u16));
+    }
+
+    fn map(&mut self, _can_assign: bool) {
+        let mut count: usize = 0;
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+
+                if count == Compiler::MAX_ELEMENTS {
+                    self.error("Can't have more than 65535 entries in a map literal.");
+                }
+
+                count += 1;
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit(Instruction::BuildMap(count as u16));
+    }
+
+    fn subscript(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit(Instruction::SetIndex);
+        } else {
+            self.emit(Instruction::GetIndex);
+        }
+    }
+
+    fn argument_list(&mut self) -> u16 {
         let mut count: usize = 0;
         if !self.check(TokenType::RightParen) {
             loop {
                 self.expression();
 
-                if count == 255 {
-                    self.error("Can't have more than 255 arguments.");
+                if count == Compiler::MAX_ARGUMENTS {
+                    self.error("Can't have more than 65535 arguments.");
                 }
 
                 count += 1;
@@ -735,14 +1441,64 @@ impl<'sourcecode> Parser<'sourcecode> {
             }
         }
         self.consume(TokenType::RightParen, "Expect ')' after arguments.");
-        count as u8
+        count as u16
     }
 
     fn grouping(&mut self, _can_assing: bool) {
+        if self.is_arrow() {
+            self.arrow();
+            return;
+        }
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
+    /// Returns true if the parenthesis just consumed starts the parameter list
+    /// of an arrow function, like `(a, b) => a + b`.
+    fn is_arrow(&mut self) -> bool {
+        let current = self.current.kind;
+        self.scanner.look_ahead(|scanner| {
+            let mut kind = current;
+            if kind == TokenType::Identifier {
+                loop {
+                    kind = scanner.scan_token().kind;
+                    if kind != TokenType::Comma {
+                        break;
+                    }
+                    if scanner.scan_token().kind != TokenType::Identifier {
+                        return false;
+                    }
+                }
+            }
+            kind == TokenType::RightParen && scanner.scan_token().kind == TokenType::EqualGreater
+        })
+    }
+
+    fn arrow(&mut self) {
+        self.push_compiler(Parser::ANONYMOUS, FunctionType::Function);
+        self.begin_scope();
+        self.parameters();
+        self.consume(TokenType::EqualGreater, "Expect '=>' after parameters.");
+        self.expression();
+        self.emit_return_value();
+        self.end_function();
+    }
+
+    /// Compiles an anonymous function expression, like `fun (a) { ... }`.
+    fn lambda(&mut self, _can_assign: bool) {
+        // Named functions can only be declared as statements.
+        if !self.matches(TokenType::LeftParen) {
+            self.error("Expect expression.");
+            return;
+        }
+        self.push_compiler(Parser::ANONYMOUS, FunctionType::Function);
+        self.begin_scope();
+        self.parameters();
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+        self.end_function();
+    }
+
     fn unary(&mut self, _can_assing: bool) {
         let operator = self.previous.kind;
         self.parse_precedence(Precedence::Unary);
@@ -756,12 +1512,20 @@ impl<'sourcecode> Parser<'sourcecode> {
     fn binary(&mut self, _can_assing: bool) {
         let operator = self.previous.kind;
         let rule = self.get_rule(operator);
-        self.parse_precedence(rule.precedence.next());
+        if operator == TokenType::StarStar {
+            // Right associative.
+            self.parse_precedence(rule.precedence);
+        } else {
+            self.parse_precedence(rule.precedence.next());
+        }
         match operator {
             TokenType::Plus => self.emit(Instruction::Add),
             TokenType::Minus => self.emit(Instruction::Substract),
             TokenType::Star => self.emit(Instruction::Multiply),
             TokenType::Slash => self.emit(Instruction::Divide),
+            TokenType::Percent => self.emit(Instruction::Modulo),
+            TokenType::StarStar => self.emit(Instruction::Power),
+            TokenType::TildeSlash => self.emit(Instruction::FloorDivide),
             TokenType::BangEqual => self.emit_two(Instruction::Equal, Instruction::Not),
             TokenType::EqualEqual => self.emit(Instruction::Equal),
             TokenType::Greater => self.emit(Instruction::Greater),
@@ -802,6 +1566,9 @@ impl<'sourcecode> Parser<'sourcecode> {
         };
 
         let can_assign = precedence <= Precedence::Assignment;
+        self.compiler
+            .expression_starts
+            .push(self.previous.span.start);
         prefix_rule(self, can_assign);
 
         while self.is_lower_precedence(precedence) {
@@ -809,13 +1576,14 @@ impl<'sourcecode> Parser<'sourcecode> {
             let infix_rule = self.get_rule(self.previous.kind).infix.unwrap();
             infix_rule(self, can_assign);
         }
+        self.compiler.expression_starts.pop();
 
         if can_assign && self.matches(TokenType::Equal) {
             self.error("Invalid assignment target.");
         }
     }
 
-    fn parse_variable(&mut self, msg: &str) -> u8 {
+    fn parse_variable(&mut self, msg: &str) -> u16 {
         self.consume(TokenType::Identifier, msg);
 
         self.declare_variable();
@@ -826,18 +1594,21 @@ impl<'sourcecode> Parser<'sourcecode> {
         self.identifier_constant(self.previous)
     }
 
-    fn identifier_constant(&mut self, token: Token) -> u8 {
+    fn identifier_constant(&mut self, token: Token) -> u16 {
         let identifier = self.gc.intern(token.lexeme.to_owned());
         let value = Value::String(identifier);
         self.make_constant(value)
     }
 
     fn declare_variable(&mut self) {
+        let name = self.previous;
         // Global variables are implicitly declared
         if self.compiler.scope_depth == 0 {
+            if self.global_constants.contains_key(name.lexeme) {
+                self.error(&format!("Can't redeclare constant '{}'.", name.lexeme));
+            }
             return;
         }
-        let name = self.previous;
         if self.compiler.is_local_declared(name) {
             self.error("Already variable with this name in this scope.");
         }
@@ -889,6 +1660,17 @@ impl<'sourcecode> Parser<'sourcecode> {
         }
     }
 
+    /// Matches an identifier with a special meaning in some places, such as
+    /// `as` in imports, which can still be used as a variable name.
+    fn matches_word(&mut self, word: &str) -> bool {
+        if self.check(TokenType::Identifier) && self.current.lexeme == word {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
     fn check(&self, kind: TokenType) -> bool {
         self.current.kind == kind
     }
@@ -906,15 +1688,20 @@ impl<'sourcecode> Parser<'sourcecode> {
             return;
         }
 
-        self.had_error = true;
         self.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
-        match token.kind {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => (),
-            _ => eprint!(" at '{}'", token.lexeme),
+        let lexeme = match token.kind {
+            TokenType::Eof => Some(String::new()),
+            TokenType::Error => None,
+            _ => Some(token.lexeme.to_owned()),
         };
-        eprintln!(": {}", msg);
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: msg.to_owned(),
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            lexeme,
+        });
     }
 
     fn synchronize(&mut self) {
@@ -929,11 +1716,18 @@ impl<'sourcecode> Parser<'sourcecode> {
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
+                | TokenType::Const
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => return,
+                | TokenType::Return
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Try
+                | TokenType::Throw
+                | TokenType::Import
+                | TokenType::Export => return,
                 _ => (),
             }
 
@@ -942,23 +1736,34 @@ impl<'sourcecode> Parser<'sourcecode> {
     }
 
     fn emit(&mut self, instruction: Instruction) -> usize {
+        let span = self.emit_span();
         self.compiler
             .function
             .chunk
-            .write(instruction, self.previous.line)
+            .write(instruction, self.previous.line, span)
     }
 
     fn emit_two(&mut self, i1: Instruction, i2: Instruction) -> usize {
-        self.compiler.function.chunk.write(i1, self.previous.line);
-        self.compiler.function.chunk.write(i2, self.previous.line)
+        self.emit(i1);
+        self.emit(i2)
+    }
+
+    /// Span of the innermost expression being compiled, up to the last token.
+    fn emit_span(&self) -> Span {
+        let end = self.previous.span.end;
+        let start = match self.compiler.expression_starts.last() {
+            Some(&start) => start,
+            None => self.previous.span.start,
+        };
+        Span::new(start, end)
     }
 
-    fn emit_return(&mut self) -> usize {
+    fn emit_return(&mut self) {
         match self.compiler.function_type {
             FunctionType::Initializer => self.emit(Instruction::GetLocal(0)),
             _ => self.emit(Instruction::Nil),
         };
-        self.emit(Instruction::Return)
+        self.emit_return_value();
     }
 
     fn start_loop(&self) -> usize {
@@ -994,9 +1799,9 @@ impl<'sourcecode> Parser<'sourcecode> {
         }
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
+    fn make_constant(&mut self, value: Value) -> u16 {
         let index = self.compiler.function.chunk.add_constant(value);
-        match u8::try_from(index) {
+        match u16::try_from(index) {
             Ok(index) => index,
             Err(_) => {
                 self.error("Too many constants in one chunk.");
@@ -0,0 +1,265 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{chunk::Value, gc::GcTraceFormatter, vm::Vm};
+
+/// How to resume execution after the VM pauses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugCommand {
+    /// Pause at the next line, entering called functions.
+    StepIn,
+    /// Pause at the next line of the current function or its callers.
+    StepOver,
+    /// Pause at the next line after the current function returns.
+    StepOut,
+    /// Run until the next breakpoint.
+    Continue,
+    /// Abort the script with a runtime error.
+    Stop,
+}
+
+/// Receives control from the VM when execution pauses at a new source line.
+///
+/// Lines are given with the canonical path of the imported module they belong
+/// to, or `None` for the script being run.
+pub trait Debugger {
+    fn has_breakpoint(&self, path: Option<&Path>, line: usize) -> bool;
+
+    /// Called with the VM paused before the first instruction of `line`. The
+    /// state of the program can be inspected through the VM.
+    fn paused(&mut self, vm: &Vm, path: Option<&Path>, line: usize) -> DebugCommand;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StepMode {
+    StepIn,
+    StepOver(usize),
+    StepOut(usize),
+    Continue,
+}
+
+/// Stepping state of a debugger attached to the VM.
+pub(crate) struct DebugSession {
+    pub debugger: Box<dyn Debugger>,
+    mode: StepMode,
+    /// Call depth and offset of the last executed instruction.
+    last: Option<(usize, usize)>,
+}
+
+impl DebugSession {
+    pub fn new(debugger: Box<dyn Debugger>) -> Self {
+        DebugSession {
+            debugger,
+            mode: StepMode::StepIn,
+            last: None,
+        }
+    }
+
+    /// Forgets the last executed instruction, before running a new script.
+    pub fn restart(&mut self) {
+        self.last = None;
+    }
+
+    /// Decides whether to pause before executing the instruction at `ip` of
+    /// a function from the module at `path`, and returns the line to pause at.
+    pub fn should_pause(
+        &mut self,
+        depth: usize,
+        ip: usize,
+        lines: &[usize],
+        path: Option<&Path>,
+    ) -> Option<usize> {
+        let previous = match self.last {
+            Some((last_depth, last_ip)) if last_depth == depth => Some(last_ip),
+            // Returning to a caller, which last executed the call.
+            Some((last_depth, _)) if last_depth > depth && ip > 0 => Some(ip - 1),
+            _ => None,
+        };
+        self.last = Some((depth, ip));
+
+        let line = lines[ip];
+        if previous.is_some_and(|previous| lines[previous] == line) {
+            return None;
+        }
+        let pause = match self.mode {
+            StepMode::StepIn => true,
+            StepMode::StepOver(start) => depth <= start,
+            StepMode::StepOut(start) => depth < start,
+            StepMode::Continue => false,
+        };
+        if pause || self.debugger.has_breakpoint(path, line) {
+            Some(line)
+        } else {
+            None
+        }
+    }
+
+    pub fn resume(&mut self, command: DebugCommand, depth: usize) {
+        self.mode = match command {
+            DebugCommand::StepIn => StepMode::StepIn,
+            DebugCommand::StepOver => StepMode::StepOver(depth),
+            DebugCommand::StepOut => StepMode::StepOut(depth),
+            DebugCommand::Continue | DebugCommand::Stop => StepMode::Continue,
+        };
+    }
+}
+
+/// Line oriented debugger that reads commands from `input`.
+pub struct ConsoleDebugger<R, W> {
+    source: Vec<String>,
+    /// Sources of the imported modules, read the first time they're shown.
+    modules: HashMap<PathBuf, Vec<String>>,
+    breakpoints: BTreeSet<(Option<PathBuf>, usize)>,
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> ConsoleDebugger<R, W> {
+    pub fn new(source: &str, input: R, output: W) -> Self {
+        ConsoleDebugger {
+            source: split_lines(source),
+            modules: HashMap::new(),
+            breakpoints: BTreeSet::new(),
+            input,
+            output,
+        }
+    }
+
+    fn show_line(&mut self, path: Option<&Path>, line: usize) {
+        let source = match path {
+            Some(path) => self.modules.entry(path.to_owned()).or_insert_with(|| {
+                fs::read_to_string(path).map_or_else(|_| Vec::new(), |source| split_lines(&source))
+            }),
+            None => &self.source,
+        };
+        let text = source.get(line - 1).map_or("", |text| text.trim());
+        match path {
+            Some(path) => writeln!(self.output, "[{} line {}] {}", path.display(), line, text),
+            None => writeln!(self.output, "[line {}] {}", line, text),
+        }
+        .unwrap();
+    }
+
+    fn show_values(&mut self, vm: &Vm, values: Vec<(String, Value)>) {
+        if values.is_empty() {
+            writeln!(self.output, "(none)").unwrap();
+        }
+        for (name, value) in values {
+            let value = GcTraceFormatter::new(value, vm.gc());
+            writeln!(self.output, "{} = {}", name, value).unwrap();
+        }
+    }
+
+    /// Sets or removes a breakpoint at `<line>` of the script, or at
+    /// `<file>:<line>` of an imported module.
+    fn set_breakpoint(&mut self, argument: Option<&str>, enable: bool) {
+        let argument = argument.unwrap_or("");
+        let (file, line) = match argument.rsplit_once(':') {
+            Some((file, line)) => (Some(file), line),
+            None => (None, argument),
+        };
+        let line = match line.parse::<usize>() {
+            Ok(line) => line,
+            Err(_) => {
+                writeln!(self.output, "Expect a line number.").unwrap();
+                return;
+            }
+        };
+        // Modules are identified by their canonical path.
+        let path = match file.map(fs::canonicalize) {
+            Some(Ok(path)) => Some(path),
+            Some(Err(_)) => {
+                writeln!(self.output, "Can't find '{}'.", file.unwrap()).unwrap();
+                return;
+            }
+            None => None,
+        };
+        let location = match file {
+            Some(file) => format!("line {} of {}", line, file),
+            None => format!("line {}", line),
+        };
+        if enable {
+            self.breakpoints.insert((path, line));
+            writeln!(self.output, "Breakpoint at {}.", location).unwrap();
+        } else {
+            self.breakpoints.remove(&(path, line));
+            writeln!(self.output, "Removed breakpoint at {}.", location).unwrap();
+        }
+    }
+
+    fn help(&mut self) {
+        let help = "\
+break <line>    (b)  set a breakpoint, at <file>:<line> in a module
+delete <line>   (d)  remove a breakpoint
+step            (s)  step into the next line
+next            (n)  step over the next line
+out             (o)  step out of the current function
+continue        (c)  run until the next breakpoint
+locals          (l)  print local variables
+upvalues        (u)  print captured variables
+globals         (g)  print global variables
+backtrace       (bt) print the call stack
+stack                print the value stack
+quit            (q)  stop the script";
+        writeln!(self.output, "{}", help).unwrap();
+    }
+}
+
+impl<R: BufRead, W: Write> Debugger for ConsoleDebugger<R, W> {
+    fn has_breakpoint(&self, path: Option<&Path>, line: usize) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|(file, at)| *at == line && file.as_deref() == path)
+    }
+
+    fn paused(&mut self, vm: &Vm, path: Option<&Path>, line: usize) -> DebugCommand {
+        self.show_line(path, line);
+        loop {
+            write!(self.output, "(debug) ").unwrap();
+            self.output.flush().unwrap();
+            let mut command = String::new();
+            if self.input.read_line(&mut command).unwrap_or(0) == 0 {
+                // Without more input, run the rest of the script.
+                self.breakpoints.clear();
+                return DebugCommand::Continue;
+            }
+            let mut words = command.split_whitespace();
+            match words.next() {
+                Some("break" | "b") => self.set_breakpoint(words.next(), true),
+                Some("delete" | "d") => self.set_breakpoint(words.next(), false),
+                Some("step" | "s") => return DebugCommand::StepIn,
+                Some("next" | "n") => return DebugCommand::StepOver,
+                Some("out" | "o") => return DebugCommand::StepOut,
+                Some("continue" | "c") => return DebugCommand::Continue,
+                Some("quit" | "q") => return DebugCommand::Stop,
+                Some("locals" | "l") => self.show_values(vm, vm.locals(0)),
+                Some("upvalues" | "u") => self.show_values(vm, vm.upvalues(0)),
+                Some("globals" | "g") => self.show_values(vm, vm.globals()),
+                Some("backtrace" | "bt") => {
+                    for frame in vm.backtrace() {
+                        writeln!(self.output, "{}", frame).unwrap();
+                    }
+                }
+                Some("stack") => {
+                    for &value in vm.stack() {
+                        write!(self.output, "[{}]", GcTraceFormatter::new(value, vm.gc())).unwrap();
+                    }
+                    writeln!(self.output).unwrap();
+                }
+                Some("help" | "h") => self.help(),
+                Some(other) => {
+                    writeln!(self.output, "Unknown command '{}'. Try 'help'.", other).unwrap()
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+fn split_lines(source: &str) -> Vec<String> {
+    source.lines().map(|line| line.to_owned()).collect()
+}
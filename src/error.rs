@@ -1,4 +1,150 @@
+use std::{fmt, path::PathBuf};
+
+use crate::scanner::Span;
+
+#[derive(Debug)]
 pub enum LoxError {
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+    /// A bytecode file that could not be loaded.
+    BytecodeError(String),
+    /// The script ran past a limit of the execution budget of the VM.
+    BudgetExceeded(Limit),
+}
+
+/// A limit of the execution budget of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Instructions,
+    Time,
+    Memory,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Instructions => write!(f, "Instruction limit exceeded."),
+            Limit::Time => write!(f, "Time limit exceeded."),
+            Limit::Memory => write!(f, "Memory limit exceeded."),
+        }
+    }
+}
+
+/// How serious a diagnostic is. Every problem found by the compiler is
+/// currently an error that prevents running the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+/// A problem found while compiling source code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
+    /// Lexeme of the offending token. `None` for scanner errors, empty at end
+    /// of file.
+    pub lexeme: Option<String>,
+}
+
+impl Diagnostic {
+    /// Formats the diagnostic followed by the offending source line.
+    pub fn render(&self, source: &str) -> String {
+        format!("{}\n{}", self, self.span.snippet(source))
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.severity)?;
+        match self.lexeme.as_deref() {
+            Some("") => write!(f, " at end")?,
+            Some(lexeme) => write!(f, " at '{}'", lexeme)?,
+            None => (),
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// One entry of a runtime error stack trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// Name of the function, or `None` for top-level script code.
+    pub function: Option<String>,
+    /// Canonical path of the imported module the function belongs to, or
+    /// `None` for the script being run.
+    pub path: Option<PathBuf>,
+    pub line: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "[{} line {}]", path.display(), self.line)?,
+            None => write!(f, "[line {}]", self.line)?,
+        }
+        match &self.function {
+            Some(name) => write!(f, " in {}()", name),
+            None => write!(f, " in script"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// Call stack at the moment of the error, innermost frame first.
+    pub trace: Vec<TraceFrame>,
+    /// Offset of the failing instruction in the innermost function's chunk.
+    pub offset: usize,
+    /// Source span of the failing instruction.
+    pub span: Span,
+    /// Canonical path of the imported module whose source `span` points
+    /// into, or `None` for the script being run.
+    pub path: Option<PathBuf>,
+}
+
+impl RuntimeError {
+    /// Creates an error without location information. Native functions return
+    /// these, and the VM fills in the stack trace when raising them.
+    pub fn new(message: impl Into<String>) -> Self {
+        RuntimeError {
+            message: message.into(),
+            trace: Vec::new(),
+            offset: 0,
+            span: Span::default(),
+            path: None,
+        }
+    }
+
+    /// Formats the error message and the offending source line, followed by
+    /// the stack trace. `source` must be the source of the module at `path`.
+    pub fn render(&self, source: &str) -> String {
+        let mut result = format!("{}\n{}", self.message, self.span.snippet(source));
+        for frame in &self.trace {
+            result.push_str(&format!("\n{}", frame));
+        }
+        result
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
 }
@@ -3,7 +3,7 @@ use std::{any::Any, collections::HashMap, fmt, hash};
 
 use fmt::Debug;
 
-use crate::chunk::{Table, Value};
+use crate::chunk::{MapKey, Table, Value, ValueTable};
 
 pub trait GcTrace {
     fn format(&self, f: &mut fmt::Formatter, gc: &Gc) -> fmt::Result;
@@ -58,7 +58,7 @@ impl<T: GcTrace> PartialEq for GcRef<T> {
     }
 }
 
-impl hash::Hash for GcRef<String> {
+impl<T: GcTrace> hash::Hash for GcRef<T> {
     fn hash<H: hash::Hasher>(&self, state: &mut H) {
         self.index.hash(state)
     }
@@ -79,6 +79,12 @@ pub struct Gc {
     grey_stack: VecDeque<usize>,
 }
 
+impl Default for Gc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Gc {
     const HEAP_GROW_FACTOR: usize = 2;
 
@@ -142,6 +148,23 @@ impl Gc {
         }
     }
 
+    /// Updates the size of an object that has grown or shrunk in place.
+    pub fn resize<T: GcTrace + 'static>(&mut self, reference: GcRef<T>) {
+        let header = self.objects[reference.index].as_mut().unwrap();
+        let size = header.obj.size() + mem::size_of::<GcObjectHeader>();
+        self.bytes_allocated = self.bytes_allocated - header.size + size;
+        header.size = size;
+    }
+
+    /// Approximate size of the objects in the heap, in bytes.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn find_interned(&self, name: &str) -> Option<GcRef<String>> {
+        self.strings.get(name).copied()
+    }
+
     pub fn deref<T: GcTrace + 'static>(&self, reference: GcRef<T>) -> &T {
         self.objects[reference.index]
             .as_ref()
@@ -165,7 +188,7 @@ impl Gc {
     fn free(&mut self, index: usize) {
         #[cfg(feature = "debug_log_gc")]
         println!("free (id:{})", index,);
-        if let Some(old) = self.objects[index].take() {       
+        if let Some(old) = self.objects[index].take() {
             self.bytes_allocated -= old.size;
             self.free_slots.push(index)
         } else {
@@ -217,7 +240,7 @@ impl Gc {
             if object.is_marked {
                 return;
             }
-    
+
             #[cfg(feature = "debug_log_gc")]
             println!(
                 "mark(id:{}, type:{}, val:{:?})",
@@ -239,6 +262,15 @@ impl Gc {
         }
     }
 
+    pub fn mark_value_table(&mut self, table: &ValueTable) {
+        for (&k, &v) in table {
+            if let MapKey::String(s) = k {
+                self.mark_object(s);
+            }
+            self.mark_value(v);
+        }
+    }
+
     #[cfg(feature = "debug_stress_gc")]
     pub fn should_gc(&self) -> bool {
         true
@@ -251,7 +283,7 @@ impl Gc {
 
     fn sweep(&mut self) {
         for i in 0..self.objects.len() {
-            if let Some(mut object) = self.objects[i].as_mut() {
+            if let Some(object) = self.objects[i].as_mut() {
                 if object.is_marked {
                     object.is_marked = false;
                 } else {
@@ -0,0 +1,23 @@
+mod bytecode;
+mod chunk;
+mod compiler;
+mod debugger;
+mod error;
+mod gc;
+mod natives;
+mod objects;
+mod profiler;
+mod scanner;
+mod vm;
+
+pub use bytecode::{deserialize_function, is_bytecode, serialize_function};
+pub use chunk::{disassemble_function, FromValue, IntoValue, Value};
+pub use compiler::compile;
+pub use debugger::{ConsoleDebugger, DebugCommand, Debugger};
+pub use error::{Diagnostic, Limit, LoxError, RuntimeError, Severity, TraceFrame};
+pub use gc::{Gc, GcRef, GcTrace, GcTraceFormatter};
+pub use natives::NativeModule;
+pub use objects::{Arity, Function, LocalVariable, NativeFunction};
+pub use profiler::{FunctionProfile, LineProfile, Profile};
+pub use scanner::Span;
+pub use vm::{Vm, VmBuilder, VmConfig};
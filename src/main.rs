@@ -1,17 +1,12 @@
-mod chunk;
-mod compiler;
-mod error;
-mod gc;
-mod objects;
-mod scanner;
-mod vm;
-
-use error::LoxError;
+use loxido::{
+    compile, deserialize_function, disassemble_function, is_bytecode, serialize_function,
+    ConsoleDebugger, Function, Gc, GcRef, LoxError, Vm, VmConfig,
+};
 use std::env;
 use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process;
-use vm::Vm;
 
 fn repl(vm: &mut Vm) {
     loop {
@@ -28,28 +23,168 @@ fn repl(vm: &mut Vm) {
     }
 }
 
-fn run_file(vm: &mut Vm, path: &str) {
-    let code = match fs::read_to_string(path) {
+fn read_file(path: &str) -> Vec<u8> {
+    match fs::read(path) {
         Ok(content) => content,
         Err(error) => {
             eprint!("Unable to read file {}: {}", path, error);
             process::exit(74);
         }
-    };
-    if let Err(error) = vm.interpret(&code) {
+    }
+}
+
+fn read_source(path: &str) -> String {
+    source_from_bytes(path, read_file(path))
+}
+
+/// Decodes the content of a file that has already been read.
+fn source_from_bytes(path: &str, content: Vec<u8>) -> String {
+    match String::from_utf8(content) {
+        Ok(code) => code,
+        Err(error) => {
+            eprint!("Unable to read file {}: {}", path, error);
+            process::exit(74);
+        }
+    }
+}
+
+fn run_file(vm: &mut Vm, path: &str) -> Result<(), LoxError> {
+    let content = read_file(path);
+    if is_bytecode(&content) {
+        vm.interpret_bytecode(&content)
+    } else {
+        vm.interpret(&source_from_bytes(path, content))
+    }
+}
+
+fn exit_on_error(result: Result<(), LoxError>) {
+    if let Err(error) = result {
         match error {
-            LoxError::CompileError => process::exit(65),
-            LoxError::RuntimeError => process::exit(70),
+            LoxError::CompileError(_) | LoxError::BytecodeError(_) => process::exit(65),
+            LoxError::RuntimeError(_) | LoxError::BudgetExceeded(_) => process::exit(70),
+        }
+    }
+}
+
+fn profile_file(vm: &mut Vm, path: &str, folded: Option<&str>) {
+    vm.enable_profiling();
+    let result = run_file(vm, path);
+    if let Some(profile) = vm.take_profile() {
+        eprint!("{}", profile.report());
+        if let Some(folded) = folded {
+            if let Err(error) = fs::write(folded, profile.folded()) {
+                eprint!("Unable to write file {}: {}", folded, error);
+                process::exit(74);
+            }
         }
     }
+    exit_on_error(result);
+}
+
+/// Compiles a source file, or loads it if it's already compiled.
+fn load_function(path: &str, gc: &mut Gc) -> GcRef<Function> {
+    let content = read_file(path);
+    let result = if is_bytecode(&content) {
+        deserialize_function(&content, gc)
+    } else {
+        compile(&source_from_bytes(path, content), gc)
+    };
+    match result {
+        Ok(function) => function,
+        Err(LoxError::CompileError(diagnostics)) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}", diagnostic);
+            }
+            process::exit(65);
+        }
+        Err(LoxError::BytecodeError(message)) => {
+            eprintln!("{}", message);
+            process::exit(65);
+        }
+        Err(LoxError::RuntimeError(_) | LoxError::BudgetExceeded(_)) => process::exit(70),
+    }
+}
+
+fn compile_file(input: &str, output: &str) {
+    let mut gc = Gc::new();
+    let function = load_function(input, &mut gc);
+    let bytes = serialize_function(function, &gc);
+    if let Err(error) = fs::write(output, bytes) {
+        eprint!("Unable to write file {}: {}", output, error);
+        process::exit(74);
+    }
+}
+
+fn disassemble_file(path: &str) {
+    let mut gc = Gc::new();
+    let function = load_function(path, &mut gc);
+    print!("{}", disassemble_function(function, &gc));
+}
+
+fn debug_file(vm: &mut Vm, path: &str) {
+    let code = read_source(path);
+    let debugger = ConsoleDebugger::new(&code, io::stdin().lock(), io::stdout());
+    vm.set_debugger(Some(Box::new(debugger)));
+    exit_on_error(vm.interpret(&code));
+}
+
+/// Creates a VM that imports modules relative to the script, or to the
+/// current directory in the REPL, and then from the directories in `LOX_PATH`.
+/// With `show_source`, errors are reported with the code that caused them.
+fn new_vm(script: Option<&str>, show_source: bool) -> Vm {
+    let dir = script
+        .and_then(|script| Path::new(script).parent())
+        .map_or_else(|| PathBuf::from("."), Path::to_path_buf);
+    let mut module_paths = vec![dir];
+    if let Some(paths) = env::var_os("LOX_PATH") {
+        module_paths.extend(env::split_paths(&paths));
+    }
+    // Scripts run from the command line are trusted with file access.
+    let config = VmConfig {
+        module_paths,
+        ..VmConfig::trusted()
+    };
+    Vm::builder()
+        .config(config)
+        .show_source(show_source)
+        .build()
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: loxido [--show-source] [script]");
+    eprintln!("       loxido compile <script> [-o <output>]");
+    eprintln!("       loxido disasm <script>");
+    eprintln!("       loxido [--show-source] debug <script>");
+    eprintln!("       loxido [--show-source] --profile [--folded <output>] <script>");
+    process::exit(64);
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut vm = Vm::new();
+    let mut args: Vec<String> = env::args().collect();
+    let show_source = args.iter().any(|arg| arg == "--show-source");
+    args.retain(|arg| arg != "--show-source");
+    let new_vm = |script: Option<&str>| new_vm(script, show_source);
     match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&mut vm, &args[1]),
-        _ => process::exit(64),
+        1 => repl(&mut new_vm(None)),
+        2 => exit_on_error(run_file(&mut new_vm(Some(&args[1])), &args[1])),
+        3 if args[1] == "disasm" => disassemble_file(&args[2]),
+        3 if args[1] == "debug" => debug_file(&mut new_vm(Some(&args[2])), &args[2]),
+        _ if args[1] == "--profile" => match &args[2..] {
+            [script] => profile_file(&mut new_vm(Some(script)), script, None),
+            [flag, folded, script] if flag == "--folded" => {
+                profile_file(&mut new_vm(Some(script)), script, Some(folded))
+            }
+            _ => usage(),
+        },
+        _ if args[1] == "compile" => {
+            let input = &args[2];
+            let output = match &args[3..] {
+                [] => Path::new(input).with_extension("loxc"),
+                [flag, output] if flag == "-o" => PathBuf::from(output),
+                _ => usage(),
+            };
+            compile_file(input, &output.to_string_lossy());
+        }
+        _ => usage(),
     }
 }
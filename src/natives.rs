@@ -0,0 +1,472 @@
+use std::{
+    cell::Cell,
+    fs,
+    path::Path,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cpu_time::ProcessTime;
+
+use crate::{
+    chunk::{MapKey, Table, Value},
+    error::RuntimeError,
+    gc::{Gc, GcRef, GcTraceFormatter},
+    objects::{Arity, List, Map, NativeFunction},
+    vm::{Vm, VmConfig},
+};
+
+pub fn define<F>(gc: &mut Gc, table: &mut Table, name: &str, arity: Arity, function: F)
+where
+    F: Fn(&mut Vm, &[Value]) -> Result<Value, RuntimeError> + 'static,
+{
+    let name = gc.intern(name.to_owned());
+    let native = NativeFunction::new(name, arity, Rc::new(function));
+    let native = gc.alloc(native);
+    table.insert(name, Value::NativeFunction(native));
+}
+
+/// Groups of native functions that a VM can make available to scripts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NativeModule {
+    /// `clock()`.
+    Time,
+    /// `panic(...)`.
+    Panic,
+    /// `readFile(path)`, limited to the readable roots of the VM.
+    Fs,
+    /// `sqrt`, `floor`, `abs`, `pow`, `min`, `max`, `random` and
+    /// `seedRandom`.
+    Math,
+    /// `num(value)` and `str(value)`.
+    Conversions,
+}
+
+pub fn define_globals(vm: &mut Vm, config: &VmConfig) {
+    for module in &config.natives {
+        match module {
+            NativeModule::Time => define_time(vm),
+            NativeModule::Panic => vm.define_native("panic", Arity::Variadic, lox_panic),
+            NativeModule::Fs => define_fs(vm),
+            NativeModule::Math => define_math(vm),
+            NativeModule::Conversions => define_conversions(vm),
+        }
+    }
+}
+
+fn define_time(vm: &mut Vm) {
+    let start_time = ProcessTime::now();
+    vm.define_native("clock", Arity::Fixed(0), move |_vm, _args| {
+        let time = start_time.elapsed().as_secs_f64();
+        Ok(Value::Number(time))
+    });
+}
+
+fn define_fs(vm: &mut Vm) {
+    vm.define_native("readFile", Arity::Fixed(1), |vm, args| {
+        let path = match args[0] {
+            Value::String(path) => vm.gc().deref::<String>(path).clone(),
+            _ => return Err(RuntimeError::new("Path must be a string.")),
+        };
+        if !vm.can_read(Path::new(&path)) {
+            return Err(RuntimeError::new(format!("Can't read '{}'.", path)));
+        }
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(Value::String(vm.intern(content))),
+            Err(error) => Err(RuntimeError::new(format!(
+                "Can't read '{}': {}.",
+                path, error
+            ))),
+        }
+    });
+}
+
+fn number_argument(value: Value) -> Result<f64, RuntimeError> {
+    match value {
+        Value::Number(n) => Ok(n),
+        _ => Err(RuntimeError::new("Argument must be a number.")),
+    }
+}
+
+fn define_math(vm: &mut Vm) {
+    vm.define_native("sqrt", Arity::Fixed(1), |_vm, args| {
+        Ok(Value::Number(number_argument(args[0])?.sqrt()))
+    });
+    vm.define_native("floor", Arity::Fixed(1), |_vm, args| {
+        Ok(Value::Number(number_argument(args[0])?.floor()))
+    });
+    vm.define_native("abs", Arity::Fixed(1), |_vm, args| {
+        Ok(Value::Number(number_argument(args[0])?.abs()))
+    });
+    vm.define_native("pow", Arity::Fixed(2), |_vm, args| {
+        let base = number_argument(args[0])?;
+        Ok(Value::Number(base.powf(number_argument(args[1])?)))
+    });
+    vm.define_native("min", Arity::Variadic, |_vm, args| {
+        fold_numbers(args, f64::min)
+    });
+    vm.define_native("max", Arity::Variadic, |_vm, args| {
+        fold_numbers(args, f64::max)
+    });
+
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64);
+    let state = Rc::new(Cell::new(seed));
+    let random_state = Rc::clone(&state);
+    vm.define_native("random", Arity::Fixed(0), move |_vm, _args| {
+        Ok(Value::Number(next_random(&random_state)))
+    });
+    vm.define_native("seedRandom", Arity::Fixed(1), move |_vm, args| {
+        state.set(number_argument(args[0])?.to_bits());
+        Ok(Value::Nil)
+    });
+}
+
+fn fold_numbers(args: &[Value], f: fn(f64, f64) -> f64) -> Result<Value, RuntimeError> {
+    let (&first, rest) = args
+        .split_first()
+        .ok_or_else(|| RuntimeError::new("Expected at least 1 argument."))?;
+    let mut result = number_argument(first)?;
+    for &arg in rest {
+        result = f(result, number_argument(arg)?);
+    }
+    Ok(Value::Number(result))
+}
+
+/// Returns a number in [0, 1) using the SplitMix64 generator, which gives the
+/// same sequence for the same seed on every platform.
+fn next_random(state: &Cell<u64>) -> f64 {
+    let next = state.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+    state.set(next);
+    let mut z = next;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn define_conversions(vm: &mut Vm) {
+    vm.define_native("num", Arity::Fixed(1), |vm, args| match args[0] {
+        Value::Number(n) => Ok(Value::Number(n)),
+        Value::String(string) => {
+            let string = vm.gc().deref::<String>(string).trim();
+            let number = string.parse::<f64>().ok().filter(|n| n.is_finite());
+            Ok(number.map_or(Value::Nil, Value::Number))
+        }
+        _ => Err(RuntimeError::new("Argument must be a string or a number.")),
+    });
+    vm.define_native("str", Arity::Fixed(1), |vm, args| match args[0] {
+        Value::String(string) => Ok(Value::String(string)),
+        value => {
+            let string = GcTraceFormatter::new(value, vm.gc()).to_string();
+            Ok(Value::String(vm.intern(string)))
+        }
+    });
+}
+
+fn lox_panic(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut terms: Vec<String> = vec![];
+
+    for &arg in args.iter() {
+        let formatter = GcTraceFormatter::new(arg, vm.gc());
+        let term = format!("{}", formatter);
+        terms.push(term);
+    }
+
+    Err(RuntimeError::new(format!("panic: {}", terms.join(", "))))
+}
+
+/// Methods available on list values. The receiver is passed as the first
+/// argument.
+pub fn list_methods(gc: &mut Gc) -> Table {
+    let mut methods = Table::new();
+    define(gc, &mut methods, "push", Arity::Fixed(1), list_push);
+    define(gc, &mut methods, "pop", Arity::Fixed(0), list_pop);
+    define(gc, &mut methods, "len", Arity::Fixed(0), list_len);
+    define(gc, &mut methods, "insert", Arity::Fixed(2), list_insert);
+    define(gc, &mut methods, "remove", Arity::Fixed(1), list_remove);
+    methods
+}
+
+/// Converts a Lox value to an index into a sequence of `len` elements.
+/// `len` itself is accepted when `allow_end` is set, for insertions.
+pub fn to_index(value: Value, len: usize, allow_end: bool) -> Result<usize, RuntimeError> {
+    let limit = if allow_end { len + 1 } else { len };
+    match value {
+        Value::Number(n) if n.fract() == 0.0 => {
+            if n >= 0.0 && n < limit as f64 {
+                Ok(n as usize)
+            } else {
+                Err(RuntimeError::new("Index out of bounds."))
+            }
+        }
+        _ => Err(RuntimeError::new("Index must be an integer.")),
+    }
+}
+
+fn receiver_list(args: &[Value]) -> GcRef<List> {
+    match args[0] {
+        Value::List(list) => list,
+        _ => panic!("List method called on a non list receiver"),
+    }
+}
+
+fn list_push(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let list = receiver_list(args);
+    vm.gc_mut().deref_mut(list).items.push(args[1]);
+    vm.resized(list);
+    Ok(Value::Nil)
+}
+
+fn list_pop(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let list = receiver_list(args);
+    match vm.gc_mut().deref_mut(list).items.pop() {
+        Some(value) => Ok(value),
+        None => Err(RuntimeError::new("Can't pop from an empty list.")),
+    }
+}
+
+fn list_len(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let list = receiver_list(args);
+    let len = vm.gc().deref(list).items.len();
+    Ok(Value::Number(len as f64))
+}
+
+fn list_insert(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let list = receiver_list(args);
+    let items = &mut vm.gc_mut().deref_mut(list).items;
+    let index = to_index(args[1], items.len(), true)?;
+    items.insert(index, args[2]);
+    vm.resized(list);
+    Ok(Value::Nil)
+}
+
+fn list_remove(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let list = vm.gc_mut().deref_mut(receiver_list(args));
+    let index = to_index(args[1], list.items.len(), false)?;
+    Ok(list.items.remove(index))
+}
+
+/// Methods available on map values. The receiver is passed as the first
+/// argument.
+pub fn map_methods(gc: &mut Gc) -> Table {
+    let mut methods = Table::new();
+    define(gc, &mut methods, "keys", Arity::Fixed(0), map_keys);
+    define(gc, &mut methods, "values", Arity::Fixed(0), map_values);
+    define(gc, &mut methods, "has", Arity::Fixed(1), map_has);
+    define(gc, &mut methods, "remove", Arity::Fixed(1), map_remove);
+    define(gc, &mut methods, "len", Arity::Fixed(0), map_len);
+    methods
+}
+
+pub fn to_map_key(value: Value) -> Result<MapKey, RuntimeError> {
+    MapKey::from_value(value)
+        .ok_or_else(|| RuntimeError::new("Map keys must be strings, numbers, booleans or nil."))
+}
+
+fn receiver_map(args: &[Value]) -> GcRef<Map> {
+    match args[0] {
+        Value::Map(map) => map,
+        _ => panic!("Map method called on a non map receiver"),
+    }
+}
+
+fn map_keys(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let map = vm.gc().deref(receiver_map(args));
+    let keys = map.entries.keys().map(|key| key.to_value()).collect();
+    let list = vm.alloc(List::new(keys));
+    Ok(Value::List(list))
+}
+
+fn map_values(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let map = vm.gc().deref(receiver_map(args));
+    let values = map.entries.values().copied().collect();
+    let list = vm.alloc(List::new(values));
+    Ok(Value::List(list))
+}
+
+fn map_has(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let key = to_map_key(args[1])?;
+    let map = vm.gc().deref(receiver_map(args));
+    Ok(Value::Bool(map.entries.contains_key(&key)))
+}
+
+fn map_remove(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let key = to_map_key(args[1])?;
+    let map = vm.gc_mut().deref_mut(receiver_map(args));
+    Ok(map.entries.remove(&key).unwrap_or(Value::Nil))
+}
+
+fn map_len(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let map = vm.gc().deref(receiver_map(args));
+    Ok(Value::Number(map.entries.len() as f64))
+}
+
+/// Methods available on string values. The receiver is passed as the first
+/// argument. Lengths and indices count characters, not bytes.
+pub fn string_methods(gc: &mut Gc) -> Table {
+    let mut methods = Table::new();
+    define(gc, &mut methods, "len", Arity::Fixed(0), string_len);
+    define(
+        gc,
+        &mut methods,
+        "substring",
+        Arity::Fixed(2),
+        string_substring,
+    );
+    define(
+        gc,
+        &mut methods,
+        "indexOf",
+        Arity::Fixed(1),
+        string_index_of,
+    );
+    define(gc, &mut methods, "split", Arity::Fixed(1), string_split);
+    define(gc, &mut methods, "trim", Arity::Fixed(0), string_trim);
+    define(gc, &mut methods, "upper", Arity::Fixed(0), string_upper);
+    define(gc, &mut methods, "lower", Arity::Fixed(0), string_lower);
+    define(
+        gc,
+        &mut methods,
+        "startsWith",
+        Arity::Fixed(1),
+        string_starts_with,
+    );
+    define(
+        gc,
+        &mut methods,
+        "endsWith",
+        Arity::Fixed(1),
+        string_ends_with,
+    );
+    define(gc, &mut methods, "replace", Arity::Fixed(2), string_replace);
+    define(gc, &mut methods, "chars", Arity::Fixed(0), string_chars);
+    methods
+}
+
+/// Returns the character of `string` at a Lox index.
+pub fn char_at(string: &str, index: Value) -> Result<String, RuntimeError> {
+    let i = to_index(index, string.chars().count(), false)?;
+    Ok(string.chars().nth(i).unwrap().to_string())
+}
+
+fn receiver_string(args: &[Value]) -> GcRef<String> {
+    match args[0] {
+        Value::String(string) => string,
+        _ => panic!("String method called on a non string receiver"),
+    }
+}
+
+fn string_argument(value: Value) -> Result<GcRef<String>, RuntimeError> {
+    match value {
+        Value::String(string) => Ok(string),
+        _ => Err(RuntimeError::new("Argument must be a string.")),
+    }
+}
+
+/// Byte offset of the character at `index`, which can be the end of `string`.
+fn byte_offset(string: &str, index: usize) -> usize {
+    string
+        .char_indices()
+        .nth(index)
+        .map_or(string.len(), |(offset, _)| offset)
+}
+
+fn string_len(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let string = vm.gc().deref(receiver_string(args));
+    Ok(Value::Number(string.chars().count() as f64))
+}
+
+fn string_substring(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let string = vm.gc().deref(receiver_string(args));
+    let len = string.chars().count();
+    let start = to_index(args[1], len, true)?;
+    let end = to_index(args[2], len, true)?;
+    if start > end {
+        return Err(RuntimeError::new("Start index is after end index."));
+    }
+    let result = string[byte_offset(string, start)..byte_offset(string, end)].to_owned();
+    Ok(Value::String(vm.intern(result)))
+}
+
+fn string_index_of(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let string = vm.gc().deref(receiver_string(args));
+    let pattern = vm.gc().deref(string_argument(args[1])?);
+    let index = match string.find(pattern.as_str()) {
+        Some(offset) => string[..offset].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Value::Number(index))
+}
+
+fn string_split(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let string = vm.gc().deref(receiver_string(args));
+    let separator = vm.gc().deref(string_argument(args[1])?);
+    if separator.is_empty() {
+        return Err(RuntimeError::new("Separator can't be empty."));
+    }
+    let parts: Vec<String> = string
+        .split(separator.as_str())
+        .map(|part| part.to_owned())
+        .collect();
+    let items = parts
+        .into_iter()
+        .map(|part| Value::String(vm.intern(part)))
+        .collect();
+    let list = vm.alloc(List::new(items));
+    Ok(Value::List(list))
+}
+
+fn string_trim(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let result = vm.gc().deref(receiver_string(args)).trim().to_owned();
+    Ok(Value::String(vm.intern(result)))
+}
+
+fn string_upper(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let result = vm.gc().deref(receiver_string(args)).to_uppercase();
+    Ok(Value::String(vm.intern(result)))
+}
+
+fn string_lower(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let result = vm.gc().deref(receiver_string(args)).to_lowercase();
+    Ok(Value::String(vm.intern(result)))
+}
+
+fn string_starts_with(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let string = vm.gc().deref(receiver_string(args));
+    let prefix = vm.gc().deref(string_argument(args[1])?);
+    Ok(Value::Bool(string.starts_with(prefix.as_str())))
+}
+
+fn string_ends_with(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let string = vm.gc().deref(receiver_string(args));
+    let suffix = vm.gc().deref(string_argument(args[1])?);
+    Ok(Value::Bool(string.ends_with(suffix.as_str())))
+}
+
+fn string_replace(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let string = vm.gc().deref(receiver_string(args));
+    let from = vm.gc().deref(string_argument(args[1])?);
+    let to = vm.gc().deref(string_argument(args[2])?);
+    if from.is_empty() {
+        return Err(RuntimeError::new("Pattern can't be empty."));
+    }
+    let result = string.replace(from.as_str(), to);
+    Ok(Value::String(vm.intern(result)))
+}
+
+fn string_chars(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    let chars: Vec<String> = vm
+        .gc()
+        .deref(receiver_string(args))
+        .chars()
+        .map(|c| c.to_string())
+        .collect();
+    let items = chars
+        .into_iter()
+        .map(|c| Value::String(vm.intern(c)))
+        .collect();
+    let list = vm.alloc(List::new(items));
+    Ok(Value::List(list))
+}
@@ -1,9 +1,11 @@
-use std::{any::Any, fmt, mem};
+use std::{any::Any, collections::HashSet, fmt, mem, path::PathBuf, rc::Rc};
 
 use crate::{
     chunk::Chunk,
-    chunk::{Instruction, Table, Value},
+    chunk::{Instruction, MapKey, Table, Value, ValueTable},
+    error::RuntimeError,
     gc::{Gc, GcRef, GcTrace},
+    scanner::Span,
     vm::Vm,
 };
 
@@ -12,7 +14,7 @@ impl GcTrace for String {
         write!(f, "{}", self)
     }
     fn size(&self) -> usize {
-        mem::size_of::<String>() + self.as_bytes().len()
+        mem::size_of::<String>() + self.len()
     }
     fn trace(&self, _gc: &mut Gc) {}
     fn as_any(&self) -> &dyn Any {
@@ -23,33 +25,79 @@ impl GcTrace for String {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct NativeFunction(pub fn(&Vm, &[Value]) -> Value);
+/// Number of arguments accepted by a native function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arity {
+    Fixed(usize),
+    Variadic,
+}
+
+pub type NativeFn = dyn Fn(&mut Vm, &[Value]) -> Result<Value, RuntimeError>;
+
+pub struct NativeFunction {
+    pub name: GcRef<String>,
+    pub arity: Arity,
+    pub function: Rc<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new(name: GcRef<String>, arity: Arity, function: Rc<NativeFn>) -> Self {
+        NativeFunction {
+            name,
+            arity,
+            function,
+        }
+    }
+}
 
 impl fmt::Debug for NativeFunction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<fn>")
+        write!(f, "<native fn {:?}>", self.name)
     }
 }
 
-impl PartialEq for NativeFunction {
-    fn eq(&self, other: &Self) -> bool {
-        std::ptr::eq(self, other)
+impl GcTrace for NativeFunction {
+    fn format(&self, f: &mut fmt::Formatter, _gc: &Gc) -> fmt::Result {
+        write!(f, "<native fn>")
+    }
+    fn size(&self) -> usize {
+        mem::size_of::<NativeFunction>()
+    }
+    fn trace(&self, gc: &mut Gc) {
+        gc.mark_object(self.name);
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
     }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct FunctionUpvalue {
-    pub index: u8,
+    pub index: u16,
     pub is_local: bool,
 }
 
+/// Debug information about a local variable: its stack slot, relative to the
+/// frame, and the range of instructions where it's in scope.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalVariable {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug)]
 pub struct Function {
     pub arity: usize,
     pub chunk: Chunk,
     pub name: GcRef<String>,
     pub upvalues: Vec<FunctionUpvalue>,
+    pub locals: Vec<LocalVariable>,
+    pub upvalue_names: Vec<String>,
 }
 
 impl Function {
@@ -59,6 +107,8 @@ impl Function {
             chunk: Chunk::new(),
             name,
             upvalues: Vec::new(),
+            locals: Vec::new(),
+            upvalue_names: Vec::new(),
         }
     }
 }
@@ -77,7 +127,10 @@ impl GcTrace for Function {
             + self.upvalues.capacity() * mem::size_of::<FunctionUpvalue>()
             + self.chunk.code.capacity() * mem::size_of::<Instruction>()
             + self.chunk.constants.capacity() * mem::size_of::<Value>()
-            + self.chunk.constants.capacity() * mem::size_of::<usize>()
+            + self.chunk.lines.capacity() * mem::size_of::<usize>()
+            + self.chunk.spans.capacity() * mem::size_of::<Span>()
+            + self.locals.capacity() * mem::size_of::<LocalVariable>()
+            + self.upvalue_names.capacity() * mem::size_of::<String>()
     }
     fn trace(&self, gc: &mut Gc) {
         gc.mark_object(self.name);
@@ -132,13 +185,16 @@ impl GcTrace for Upvalue {
 pub struct Closure {
     pub function: GcRef<Function>,
     pub upvalues: Vec<GcRef<Upvalue>>,
+    /// Module whose globals the closure uses, or `None` for the main script.
+    pub module: Option<GcRef<Module>>,
 }
 
 impl Closure {
-    pub fn new(function: GcRef<Function>) -> Self {
+    pub fn new(function: GcRef<Function>, module: Option<GcRef<Module>>) -> Self {
         Closure {
             function,
             upvalues: Vec::new(),
+            module,
         }
     }
 }
@@ -156,6 +212,9 @@ impl GcTrace for Closure {
         for &upvalue in &self.upvalues {
             gc.mark_object(upvalue);
         }
+        if let Some(module) = self.module {
+            gc.mark_object(module);
+        }
     }
     fn as_any(&self) -> &dyn Any {
         self
@@ -268,3 +327,135 @@ impl GcTrace for BoundMethod {
         self
     }
 }
+
+#[derive(Debug)]
+pub struct List {
+    pub items: Vec<Value>,
+}
+
+impl List {
+    pub fn new(items: Vec<Value>) -> Self {
+        List { items }
+    }
+}
+
+impl GcTrace for List {
+    fn format(&self, f: &mut fmt::Formatter, gc: &Gc) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            item.format(f, gc)?;
+        }
+        write!(f, "]")
+    }
+    fn size(&self) -> usize {
+        mem::size_of::<List>() + self.items.capacity() * mem::size_of::<Value>()
+    }
+    fn trace(&self, gc: &mut Gc) {
+        for &item in &self.items {
+            gc.mark_value(item);
+        }
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct Map {
+    pub entries: ValueTable,
+}
+
+impl Map {
+    pub fn new(entries: ValueTable) -> Self {
+        Map { entries }
+    }
+}
+
+impl GcTrace for Map {
+    fn format(&self, f: &mut fmt::Formatter, gc: &Gc) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, (key, value)) in self.entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            key.to_value().format(f, gc)?;
+            write!(f, ": ")?;
+            value.format(f, gc)?;
+        }
+        write!(f, "}}")
+    }
+    fn size(&self) -> usize {
+        mem::size_of::<Map>()
+            + self.entries.capacity() * (mem::size_of::<MapKey>() + mem::size_of::<Value>())
+    }
+    fn trace(&self, gc: &mut Gc) {
+        gc.mark_value_table(&self.entries);
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A script loaded with `import`, which has its own global variables.
+#[derive(Debug)]
+pub struct Module {
+    /// Path used to import the module the first time.
+    pub name: GcRef<String>,
+    /// Canonical path of the script.
+    pub path: PathBuf,
+    pub globals: Table,
+    /// Globals that other modules can read.
+    pub exports: HashSet<GcRef<String>>,
+    /// Globals declared with `const`.
+    pub constants: HashSet<GcRef<String>>,
+    /// Whether the top-level code of the module finished running.
+    pub loaded: bool,
+}
+
+impl Module {
+    pub fn new(name: GcRef<String>, path: PathBuf, globals: Table) -> Self {
+        Module {
+            name,
+            path,
+            globals,
+            exports: HashSet::new(),
+            constants: HashSet::new(),
+            loaded: false,
+        }
+    }
+}
+
+impl GcTrace for Module {
+    fn format(&self, f: &mut fmt::Formatter, gc: &Gc) -> fmt::Result {
+        let name = gc.deref(self.name);
+        write!(f, "<module {}>", name)
+    }
+    fn size(&self) -> usize {
+        mem::size_of::<Module>()
+            + self.globals.capacity() * (mem::size_of::<GcRef<String>>() + mem::size_of::<Value>())
+            + (self.exports.capacity() + self.constants.capacity())
+                * mem::size_of::<GcRef<String>>()
+    }
+    fn trace(&self, gc: &mut Gc) {
+        gc.mark_object(self.name);
+        gc.mark_table(&self.globals);
+        for &name in self.exports.iter().chain(&self.constants) {
+            gc.mark_object(name);
+        }
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    gc::{Gc, GcRef},
+    objects::{Closure, Function},
+};
+
+/// Instructions executed and time spent in a function, excluding its callees.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+/// Instructions executed and time spent in a source line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineProfile {
+    /// Canonical path of the imported module, or `None` for the script.
+    pub path: Option<PathBuf>,
+    pub line: usize,
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+#[derive(Default)]
+struct Counter {
+    instructions: u64,
+    time: Duration,
+}
+
+struct FunctionEntry {
+    name: String,
+    /// Index of the module where the function is declared.
+    source: usize,
+    calls: u64,
+    counter: Counter,
+}
+
+struct LineEntry {
+    source: usize,
+    line: usize,
+    counter: Counter,
+}
+
+/// A node in the tree of call stacks seen while profiling.
+struct StackEntry {
+    parent: Option<usize>,
+    function: usize,
+    counter: Counter,
+}
+
+/// Measurements collected while the VM runs with profiling enabled.
+///
+/// Time spent in an instruction is measured until the next one starts, so
+/// natives are counted as part of the instruction that called them.
+pub struct Profile {
+    function_ids: HashMap<GcRef<Function>, usize>,
+    functions: Vec<FunctionEntry>,
+    stack_ids: HashMap<(Option<usize>, usize), usize>,
+    stacks: Vec<StackEntry>,
+    /// Paths of the modules, `None` being the script.
+    sources: Vec<Option<PathBuf>>,
+    line_ids: HashMap<(usize, usize), usize>,
+    lines: Vec<LineEntry>,
+    /// Closure and call stack of every frame in the VM.
+    frames: Vec<(GcRef<Closure>, usize)>,
+    /// Function, stack and line being measured, and when the measure started.
+    current: Option<(usize, usize, usize, Instant)>,
+}
+
+impl Profile {
+    pub(crate) fn new() -> Self {
+        Profile {
+            function_ids: HashMap::new(),
+            functions: Vec::new(),
+            stack_ids: HashMap::new(),
+            stacks: Vec::new(),
+            sources: Vec::new(),
+            line_ids: HashMap::new(),
+            lines: Vec::new(),
+            frames: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// Returns true if the frame at `depth` running `closure` is the one the
+    /// counters are being recorded for.
+    pub(crate) fn is_current(&self, depth: usize, closure: GcRef<Closure>) -> bool {
+        self.frames.len() == depth && self.frames.last().map(|frame| frame.0) == Some(closure)
+    }
+
+    pub(crate) fn has_function(&self, function: GcRef<Function>) -> bool {
+        self.function_ids.contains_key(&function)
+    }
+
+    pub(crate) fn add_function(
+        &mut self,
+        function: GcRef<Function>,
+        name: String,
+        path: Option<PathBuf>,
+    ) {
+        let source = match self.sources.iter().position(|source| *source == path) {
+            Some(source) => source,
+            None => {
+                self.sources.push(path);
+                self.sources.len() - 1
+            }
+        };
+        self.function_ids.insert(function, self.functions.len());
+        self.functions.push(FunctionEntry {
+            name,
+            source,
+            calls: 0,
+            counter: Counter::default(),
+        });
+    }
+
+    /// Makes the frame at `depth` the current one, either because it has
+    /// been called or because it's been returned to.
+    pub(crate) fn enter(
+        &mut self,
+        depth: usize,
+        closure: GcRef<Closure>,
+        function: GcRef<Function>,
+        call: bool,
+    ) {
+        if self.frames.len() >= depth && self.frames[depth - 1].0 == closure && !call {
+            self.frames.truncate(depth);
+            return;
+        }
+        self.frames.truncate(depth - 1);
+        let parent = self.frames.last().map(|frame| frame.1);
+        let function = self.function_ids[&function];
+        if call {
+            self.functions[function].calls += 1;
+        }
+        let stacks = &mut self.stacks;
+        let stack = *self.stack_ids.entry((parent, function)).or_insert_with(|| {
+            stacks.push(StackEntry {
+                parent,
+                function,
+                counter: Counter::default(),
+            });
+            stacks.len() - 1
+        });
+        self.frames.push((closure, stack));
+    }
+
+    /// Counts an instruction of the current frame.
+    pub(crate) fn instruction(&mut self, line: usize) {
+        let stack = self.frames.last().unwrap().1;
+        let function = self.stacks[stack].function;
+        let index = match self.current {
+            Some((f, s, l, _)) if f == function && s == stack && self.lines[l].line == line => l,
+            _ => {
+                let now = Instant::now();
+                self.finish_measure(now);
+                let source = self.functions[function].source;
+                let lines = &mut self.lines;
+                let index = *self.line_ids.entry((source, line)).or_insert_with(|| {
+                    lines.push(LineEntry {
+                        source,
+                        line,
+                        counter: Counter::default(),
+                    });
+                    lines.len() - 1
+                });
+                self.current = Some((function, stack, index, now));
+                index
+            }
+        };
+        self.functions[function].counter.instructions += 1;
+        self.stacks[stack].counter.instructions += 1;
+        self.lines[index].counter.instructions += 1;
+    }
+
+    /// Stops measuring, at the end of a script.
+    pub(crate) fn finish(&mut self) {
+        self.finish_measure(Instant::now());
+        self.current = None;
+        self.frames.clear();
+    }
+
+    fn finish_measure(&mut self, now: Instant) {
+        if let Some((function, stack, line, start)) = self.current {
+            let elapsed = now - start;
+            self.functions[function].counter.time += elapsed;
+            self.stacks[stack].counter.time += elapsed;
+            self.lines[line].counter.time += elapsed;
+        }
+    }
+
+    /// Functions seen so far, which must be kept alive so that their
+    /// references don't get reused for other functions.
+    pub(crate) fn trace(&self, gc: &mut Gc) {
+        for &function in self.function_ids.keys() {
+            gc.mark_object(function);
+        }
+    }
+
+    /// Functions sorted by time spent, slowest first.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions: Vec<FunctionProfile> = self
+            .functions
+            .iter()
+            .map(|function| FunctionProfile {
+                name: function.name.clone(),
+                calls: function.calls,
+                instructions: function.counter.instructions,
+                time: function.counter.time,
+            })
+            .collect();
+        functions.sort_by(|a, b| b.time.cmp(&a.time).then(a.name.cmp(&b.name)));
+        functions
+    }
+
+    /// Lines that executed at least one instruction, sorted by time spent,
+    /// slowest first.
+    pub fn lines(&self) -> Vec<LineProfile> {
+        let mut lines: Vec<LineProfile> = self
+            .lines
+            .iter()
+            .map(|entry| LineProfile {
+                path: self.sources[entry.source].clone(),
+                line: entry.line,
+                instructions: entry.counter.instructions,
+                time: entry.counter.time,
+            })
+            .collect();
+        lines.sort_by(|a, b| {
+            b.time
+                .cmp(&a.time)
+                .then(a.path.cmp(&b.path))
+                .then(a.line.cmp(&b.line))
+        });
+        lines
+    }
+
+    /// Formats a report of the time spent per function and per line.
+    pub fn report(&self) -> String {
+        let total: Duration = self.functions.iter().map(|f| f.counter.time).sum();
+        let percent = |time: Duration| {
+            if total.is_zero() {
+                0.0
+            } else {
+                100.0 * time.as_secs_f64() / total.as_secs_f64()
+            }
+        };
+        let mut out = String::new();
+        writeln!(out, "== Functions ==").unwrap();
+        writeln!(
+            out,
+            "{:>12} {:>7} {:>14} {:>10}  Function",
+            "Time (ms)", "%", "Instructions", "Calls"
+        )
+        .unwrap();
+        for function in self.functions() {
+            writeln!(
+                out,
+                "{:>12.3} {:>6.1}% {:>14} {:>10}  {}",
+                function.time.as_secs_f64() * 1000.0,
+                percent(function.time),
+                function.instructions,
+                function.calls,
+                function.name
+            )
+            .unwrap();
+        }
+        writeln!(out, "\n== Lines ==").unwrap();
+        writeln!(
+            out,
+            "{:>12} {:>7} {:>14}  Line",
+            "Time (ms)", "%", "Instructions"
+        )
+        .unwrap();
+        for line in self.lines() {
+            let location = match &line.path {
+                Some(path) => format!("{}:{}", path.display(), line.line),
+                None => line.line.to_string(),
+            };
+            writeln!(
+                out,
+                "{:>12.3} {:>6.1}% {:>14}  {}",
+                line.time.as_secs_f64() * 1000.0,
+                percent(line.time),
+                line.instructions,
+                location
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Formats the time spent in each call stack, in nanoseconds, as folded
+    /// stacks: one `outer;inner count` line per stack, the input format of
+    /// flamegraph tools.
+    pub fn folded(&self) -> String {
+        let mut stacks: Vec<(String, u128)> = self
+            .stacks
+            .iter()
+            .map(|entry| {
+                let mut names = vec![self.functions[entry.function].name.as_str()];
+                let mut parent = entry.parent;
+                while let Some(index) = parent {
+                    names.push(&self.functions[self.stacks[index].function].name);
+                    parent = self.stacks[index].parent;
+                }
+                names.reverse();
+                (names.join(";"), entry.counter.time.as_nanos())
+            })
+            .collect();
+        stacks.sort();
+        let mut out = String::new();
+        for (stack, time) in stacks {
+            writeln!(out, "{} {}", stack, time).unwrap();
+        }
+        out
+    }
+}
@@ -6,6 +6,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -13,37 +16,53 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
 
     // One or two character tokens.
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
+    EqualGreater,
     Greater,
     GreaterEqual,
     Less,
     LessEqual,
+    StarStar,
+    TildeSlash,
 
     // Literals.
     Identifier,
     String,
+    /// Part of a string literal that ends with the start of an interpolated
+    /// expression.
+    Interpolation,
     Number,
 
     // Keywords.
     And,
+    Break,
+    Catch,
     Class,
+    Const,
+    Continue,
     Else,
+    Export,
     False,
+    Finally,
     For,
     Fun,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -51,10 +70,60 @@ pub enum TokenType {
     Eof,
 }
 
+/// Largest char boundary of `source` that isn't after `index`.
+fn floor_char_boundary(source: &str, index: usize) -> usize {
+    let mut index = index.min(source.len());
+    while !source.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Byte range of a piece of source code.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Renders the source line containing the start of the span, with the
+    /// spanned text underlined with carets. A span from another source is
+    /// clamped to it rather than rejected.
+    pub fn snippet(&self, source: &str) -> String {
+        let start = floor_char_boundary(source, self.start);
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let line_number = source[..start].matches('\n').count() + 1;
+        let text = source[line_start..line_end].trim_end_matches('\r');
+        let padding = source[line_start..start].chars().count();
+        let end = floor_char_boundary(source, self.end.clamp(start, line_end));
+        let width = source[start..end].chars().count().max(1);
+        let gutter = line_number.to_string().len();
+        format!(
+            "{:>gutter$} | {}\n{:>gutter$} | {}{}",
+            line_number,
+            text,
+            "",
+            " ".repeat(padding),
+            "^".repeat(width),
+            gutter = gutter
+        )
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Token<'sourcecode> {
     pub kind: TokenType,
     pub line: usize,
+    pub column: usize,
+    pub span: Span,
     pub lexeme: &'sourcecode str,
 }
 
@@ -64,6 +133,8 @@ impl<'sourcecode> Token<'sourcecode> {
             kind: TokenType::Error,
             lexeme: text,
             line: 0,
+            column: 0,
+            span: Span::default(),
         }
     }
 }
@@ -74,25 +145,40 @@ pub struct Scanner<'sourcecode> {
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,
+    column: usize,
+    /// Offset of the character at `column`.
+    column_offset: usize,
+    /// Braces opened inside each string interpolation being scanned.
+    interpolations: Vec<usize>,
 }
 
 impl<'sourcecode> Scanner<'sourcecode> {
-    pub fn new(code: &'sourcecode str) -> Scanner {
-        let mut keywords = HashMap::with_capacity(16);
+    pub fn new(code: &'sourcecode str) -> Scanner<'sourcecode> {
+        let mut keywords = HashMap::with_capacity(25);
         keywords.insert("and", TokenType::And);
+        keywords.insert("break", TokenType::Break);
+        keywords.insert("catch", TokenType::Catch);
         keywords.insert("class", TokenType::Class);
+        keywords.insert("const", TokenType::Const);
+        keywords.insert("continue", TokenType::Continue);
         keywords.insert("else", TokenType::Else);
+        keywords.insert("export", TokenType::Export);
         keywords.insert("false", TokenType::False);
+        keywords.insert("finally", TokenType::Finally);
         keywords.insert("for", TokenType::For);
         keywords.insert("fun", TokenType::Fun);
         keywords.insert("if", TokenType::If);
+        keywords.insert("import", TokenType::Import);
         keywords.insert("nil", TokenType::Nil);
         keywords.insert("or", TokenType::Or);
         keywords.insert("print", TokenType::Print);
         keywords.insert("return", TokenType::Return);
         keywords.insert("super", TokenType::Super);
         keywords.insert("this", TokenType::This);
+        keywords.insert("throw", TokenType::Throw);
         keywords.insert("true", TokenType::True);
+        keywords.insert("try", TokenType::Try);
         keywords.insert("var", TokenType::Var);
         keywords.insert("while", TokenType::While);
 
@@ -102,12 +188,47 @@ impl<'sourcecode> Scanner<'sourcecode> {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            column: 1,
+            column_offset: 0,
+            interpolations: Vec::new(),
         }
     }
 
+    /// Runs `scan` to look at the tokens ahead, and then rewinds the scanner
+    /// to where it was.
+    pub fn look_ahead<T>(&mut self, scan: impl FnOnce(&mut Self) -> T) -> T {
+        let position = (
+            self.start,
+            self.current,
+            self.line,
+            self.line_start,
+            self.column,
+            self.column_offset,
+        );
+        let interpolations = self.interpolations.clone();
+        let result = scan(self);
+        (
+            self.start,
+            self.current,
+            self.line,
+            self.line_start,
+            self.column,
+            self.column_offset,
+        ) = position;
+        self.interpolations = interpolations;
+        result
+    }
+
     pub fn scan_token(&mut self) -> Token<'sourcecode> {
         self.skip_whitespace();
         self.start = self.current;
+        if self.column_offset < self.line_start {
+            self.column = 1;
+            self.column_offset = self.line_start;
+        }
+        self.column += self.code[self.column_offset..self.start].chars().count();
+        self.column_offset = self.start;
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
         }
@@ -115,18 +236,40 @@ impl<'sourcecode> Scanner<'sourcecode> {
         match self.advance() {
             b'(' => self.make_token(TokenType::LeftParen),
             b')' => self.make_token(TokenType::RightParen),
-            b'{' => self.make_token(TokenType::LeftBrace),
-            b'}' => self.make_token(TokenType::RightBrace),
+            b'{' => {
+                if let Some(braces) = self.interpolations.last_mut() {
+                    *braces += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            b'}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
+                }
+                Some(braces) => {
+                    *braces -= 1;
+                    self.make_token(TokenType::RightBrace)
+                }
+                None => self.make_token(TokenType::RightBrace),
+            },
+            b'[' => self.make_token(TokenType::LeftBracket),
+            b']' => self.make_token(TokenType::RightBracket),
             b';' => self.make_token(TokenType::Semicolon),
+            b':' => self.make_token(TokenType::Colon),
             b',' => self.make_token(TokenType::Comma),
             b'.' => self.make_token(TokenType::Dot),
             b'-' => self.make_token(TokenType::Minus),
             b'+' => self.make_token(TokenType::Plus),
             b'/' => self.make_token(TokenType::Slash),
+            b'*' if self.matches(b'*') => self.make_token(TokenType::StarStar),
             b'*' => self.make_token(TokenType::Star),
+            b'%' => self.make_token(TokenType::Percent),
+            b'~' if self.matches(b'/') => self.make_token(TokenType::TildeSlash),
             b'!' if self.matches(b'=') => self.make_token(TokenType::BangEqual),
             b'!' => self.make_token(TokenType::Bang),
             b'=' if self.matches(b'=') => self.make_token(TokenType::EqualEqual),
+            b'=' if self.matches(b'>') => self.make_token(TokenType::EqualGreater),
             b'=' => self.make_token(TokenType::Equal),
             b'<' if self.matches(b'=') => self.make_token(TokenType::LessEqual),
             b'<' => self.make_token(TokenType::Less),
@@ -135,7 +278,13 @@ impl<'sourcecode> Scanner<'sourcecode> {
             b'"' => self.string(),
             c if is_digit(c) => self.number(),
             c if is_alpha(c) => self.identifier(),
-            _ => self.error_token("Unexpected character."),
+            _ => {
+                // Skip the rest of a multi-byte character.
+                while self.peek() & 0b1100_0000 == 0b1000_0000 {
+                    self.advance();
+                }
+                self.error_token("Unexpected character.")
+            }
         }
     }
 
@@ -152,6 +301,8 @@ impl<'sourcecode> Scanner<'sourcecode> {
             kind,
             lexeme: self.lexeme(),
             line: self.line,
+            column: self.column,
+            span: Span::new(self.start, self.current),
         }
     }
 
@@ -175,6 +326,8 @@ impl<'sourcecode> Scanner<'sourcecode> {
             kind: TokenType::Error,
             lexeme: message,
             line: self.line,
+            column: self.column,
+            span: Span::new(self.start, self.current),
         }
     }
 
@@ -184,6 +337,11 @@ impl<'sourcecode> Scanner<'sourcecode> {
         char
     }
 
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn matches(&mut self, expected: u8) -> bool {
         if self.is_at_end() || self.peek() != expected {
             false
@@ -200,8 +358,8 @@ impl<'sourcecode> Scanner<'sourcecode> {
                     self.advance();
                 }
                 b'\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
                 b'/' if self.peek_next() == b'/' => {
                     while self.peek() != b'\n' && !self.is_at_end() {
@@ -213,19 +371,56 @@ impl<'sourcecode> Scanner<'sourcecode> {
         }
     }
 
+    /// Scans the rest of a string literal, or the part up to the next
+    /// interpolated expression.
     fn string(&mut self) -> Token<'sourcecode> {
-        while self.peek() != b'"' && !self.is_at_end() {
-            if self.peek() == b'\n' {
-                self.line += 1;
+        let mut valid = true;
+        let kind = loop {
+            if self.is_at_end() {
+                return self.error_token("Unterminated string.");
             }
-            self.advance();
+            match self.advance() {
+                b'"' => break TokenType::String,
+                b'\n' => self.new_line(),
+                b'\\' => valid = self.escape() && valid,
+                b'$' if self.matches(b'{') => {
+                    self.interpolations.push(0);
+                    break TokenType::Interpolation;
+                }
+                _ => (),
+            }
+        };
+        if valid {
+            self.make_token(kind)
+        } else {
+            self.error_token("Invalid escape sequence.")
         }
+    }
 
-        if self.is_at_end() {
-            self.error_token("Unterminated string.")
-        } else {
-            self.advance();
-            self.make_token(TokenType::String)
+    /// Checks the escape sequence after a backslash.
+    fn escape(&mut self) -> bool {
+        if self.is_at_end() || self.peek() == b'\n' {
+            return false;
+        }
+        match self.advance() {
+            b'n' | b't' | b'r' | b'0' | b'"' | b'\\' | b'$' => true,
+            b'u' => {
+                if !self.matches(b'{') {
+                    return false;
+                }
+                let start = self.current;
+                while self.peek().is_ascii_hexdigit() {
+                    self.advance();
+                }
+                let digits = &self.code[start..self.current];
+                self.matches(b'}')
+                    && (1..=6).contains(&digits.len())
+                    && u32::from_str_radix(digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .is_some()
+            }
+            _ => false,
         }
     }
 
@@ -259,6 +454,39 @@ impl<'sourcecode> Scanner<'sourcecode> {
     }
 }
 
+/// Returns the text of a string literal token, or a part of an interpolated
+/// one, with its escape sequences replaced.
+pub fn string_value(lexeme: &str) -> String {
+    // Parts start with either '"' or '}', and end with either '"' or "${".
+    let end = if lexeme.ends_with('"') { 1 } else { 2 };
+    let text = &lexeme[1..lexeme.len() - end];
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('u') => {
+                let digits: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .expect("Escape sequences are checked by the scanner")
+            }
+            Some(c) => c,
+            None => unreachable!("Escape sequences are checked by the scanner"),
+        };
+        result.push(escaped);
+    }
+    result
+}
+
 fn is_digit(c: u8) -> bool {
     c.is_ascii_digit()
 }
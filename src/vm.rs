@@ -1,53 +1,402 @@
-use cpu_time::ProcessTime;
 use fmt::Debug;
 
 use crate::{
-    chunk::{Chunk, Instruction, Table, Value},
+    bytecode::deserialize_function,
+    chunk::{Chunk, FromValue, Instruction, IntoValue, MapKey, Table, Value, ValueTable},
     compiler::compile,
-    error::LoxError,
+    debugger::{DebugCommand, DebugSession, Debugger},
+    error::{Diagnostic, Limit, LoxError, RuntimeError, TraceFrame},
     gc::{Gc, GcRef, GcTrace, GcTraceFormatter},
-    objects::{BoundMethod, Class, Closure, Instance, NativeFunction, Upvalue},
+    natives::{self, NativeModule},
+    objects::{
+        Arity, BoundMethod, Class, Closure, Function, Instance, List, Map, Module, NativeFunction,
+        Upvalue,
+    },
+    profiler::Profile,
 };
-use std::fmt;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Write as _},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// Base class of exceptions. The VM fills in `stack` when an instance is
+/// thrown.
+const ERROR_CLASS: &str = "
+class Error {
+  init(message) {
+    this.message = message;
+    this.stack = nil;
+  }
+}";
 
 pub struct Vm {
     gc: Gc,
     frames: Vec<CallFrame>,
     stack: Vec<Value>,
     globals: Table,
+    /// Globals of the main script declared with `const`.
+    constants: HashSet<GcRef<String>>,
+    /// Globals defined by the VM and the host, copied into every module.
+    builtins: Table,
+    /// Imported modules by canonical path.
+    modules: HashMap<PathBuf, GcRef<Module>>,
+    module_paths: Vec<PathBuf>,
+    readable_roots: Option<Vec<PathBuf>>,
     open_upvalues: Vec<GcRef<Upvalue>>,
     init_string: GcRef<String>,
-    start_time: ProcessTime,
+    message_string: GcRef<String>,
+    stack_string: GcRef<String>,
+    /// Class of the exceptions raised for runtime errors.
+    error_class: Option<GcRef<Class>>,
+    /// Value being thrown by a `throw` statement.
+    exception: Option<Value>,
+    list_methods: Table,
+    map_methods: Table,
+    string_methods: Table,
+    in_native: bool,
+    debug_session: Option<DebugSession>,
+    profile: Option<Profile>,
+    max_frames: usize,
+    max_stack: usize,
+    budget: Budget,
+    print: bool,
+    output: Box<dyn Write>,
+    error_output: Box<dyn Write>,
+    show_source: bool,
+    /// Sources of the imported modules, kept to show the errors in them.
+    sources: HashMap<PathBuf, String>,
 }
 
-impl Vm {
-    const MAX_FRAMES: usize = 64;
-    const STACK_SIZE: usize = Vm::MAX_FRAMES * (std::u8::MAX as usize) + 1;
+/// Capabilities given to the scripts run by a [`Vm`].
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+    /// Native modules defined as globals.
+    pub natives: Vec<NativeModule>,
+    /// Whether `print` writes its output or discards it.
+    pub print: bool,
+    /// Directories whose files can be read by the `Fs` module and imported,
+    /// or `None` to allow reading any file.
+    pub readable_roots: Option<Vec<PathBuf>>,
+    /// Directories where imported modules are searched for, after the
+    /// directory of the importing module.
+    pub module_paths: Vec<PathBuf>,
+}
+
+impl VmConfig {
+    /// Everything enabled, for trusted scripts.
+    pub fn trusted() -> Self {
+        VmConfig {
+            natives: vec![
+                NativeModule::Time,
+                NativeModule::Panic,
+                NativeModule::Fs,
+                NativeModule::Math,
+                NativeModule::Conversions,
+            ],
+            print: true,
+            readable_roots: None,
+            module_paths: Vec::new(),
+        }
+    }
+
+    /// Only natives without side effects, and no output, for untrusted
+    /// scripts.
+    pub fn sandboxed() -> Self {
+        VmConfig {
+            natives: vec![
+                NativeModule::Time,
+                NativeModule::Panic,
+                NativeModule::Math,
+                NativeModule::Conversions,
+            ],
+            print: false,
+            readable_roots: Some(Vec::new()),
+            module_paths: Vec::new(),
+        }
+    }
+}
+
+/// The natives without side effects, with output, but without access to the
+/// filesystem. Scripts can't read files or import modules.
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            print: true,
+            ..Self::sandboxed()
+        }
+    }
+}
 
+/// Configures the limits of a [`Vm`] before creating it.
+pub struct VmBuilder {
+    max_frames: usize,
+    max_stack: usize,
+    max_instructions: Option<u64>,
+    timeout: Option<Duration>,
+    max_heap: Option<usize>,
+    config: VmConfig,
+    output: Box<dyn Write>,
+    error_output: Box<dyn Write>,
+    show_source: bool,
+}
+
+impl VmBuilder {
     pub fn new() -> Self {
+        VmBuilder {
+            max_frames: 16 * 1024,
+            max_stack: 1024 * 1024,
+            max_instructions: None,
+            timeout: None,
+            max_heap: None,
+            config: VmConfig::default(),
+            output: Box::new(io::stdout()),
+            error_output: Box::new(io::stderr()),
+            show_source: false,
+        }
+    }
+
+    /// Maximum depth of nested calls before a stack overflow.
+    pub fn max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// Maximum number of values in the stack before a stack overflow.
+    pub fn max_stack(mut self, max_stack: usize) -> Self {
+        self.max_stack = max_stack;
+        self
+    }
+
+    /// Maximum number of instructions executed by each script.
+    pub fn max_instructions(mut self, max_instructions: u64) -> Self {
+        self.max_instructions = Some(max_instructions);
+        self
+    }
+
+    /// Maximum time each script can run for. It's checked every few
+    /// instructions, so a long native call can overrun it.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum size of the heap in bytes, as counted by
+    /// [`Gc::bytes_allocated`]. Objects kept alive by globals count against
+    /// later scripts too.
+    pub fn max_heap(mut self, max_heap: usize) -> Self {
+        self.max_heap = Some(max_heap);
+        self
+    }
+
+    /// Capabilities of the scripts run by the VM.
+    pub fn config(mut self, config: VmConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Where `print` writes to. Defaults to the standard output.
+    pub fn output(mut self, output: impl Write + 'static) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Where compile and runtime errors are reported. Defaults to the
+    /// standard error.
+    pub fn error_output(mut self, error_output: impl Write + 'static) -> Self {
+        self.error_output = Box::new(error_output);
+        self
+    }
+
+    /// Whether reported errors are followed by the source line where they
+    /// happened, with the failing code underlined. Off by default, which
+    /// keeps the reports in the format of clox.
+    pub fn show_source(mut self, show_source: bool) -> Self {
+        self.show_source = show_source;
+        self
+    }
+
+    pub fn build(self) -> Vm {
+        Vm::with_builder(self)
+    }
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        VmBuilder::new().build()
+    }
+
+    pub fn builder() -> VmBuilder {
+        VmBuilder::new()
+    }
+
+    fn with_builder(builder: VmBuilder) -> Self {
         let mut gc = Gc::new();
         let init_string = gc.intern("init".to_owned());
+        let message_string = gc.intern("message".to_owned());
+        let stack_string = gc.intern("stack".to_owned());
+        let list_methods = natives::list_methods(&mut gc);
+        let map_methods = natives::map_methods(&mut gc);
+        let string_methods = natives::string_methods(&mut gc);
 
         let mut vm = Self {
             gc,
-            frames: Vec::with_capacity(Vm::MAX_FRAMES),
-            stack: Vec::with_capacity(Vm::STACK_SIZE),
+            frames: Vec::with_capacity(64),
+            stack: Vec::with_capacity(1024),
             globals: Table::new(),
-            open_upvalues: Vec::with_capacity(Vm::STACK_SIZE),
+            constants: HashSet::new(),
+            builtins: Table::new(),
+            modules: HashMap::new(),
+            module_paths: builder.config.module_paths.clone(),
+            readable_roots: builder.config.readable_roots.as_ref().map(|roots| {
+                // Roots that don't exist can't contain any file.
+                roots
+                    .iter()
+                    .filter_map(|root| root.canonicalize().ok())
+                    .collect()
+            }),
+            open_upvalues: Vec::new(),
             init_string,
-            start_time: ProcessTime::now(),
+            message_string,
+            stack_string,
+            error_class: None,
+            exception: None,
+            list_methods,
+            map_methods,
+            string_methods,
+            in_native: false,
+            debug_session: None,
+            profile: None,
+            max_frames: builder.max_frames,
+            max_stack: builder.max_stack,
+            budget: Budget::new(None, None, None),
+            print: builder.config.print,
+            output: builder.output,
+            error_output: builder.error_output,
+            show_source: builder.show_source,
+            sources: HashMap::new(),
         };
-        vm.define_native("clock", NativeFunction(clock));
-        vm.define_native("panic", NativeFunction(lox_panic));
+        natives::define_globals(&mut vm, &builder.config);
+        vm.define_error_class();
+        vm.builtins = vm.globals.clone();
+        vm.budget = Budget::new(builder.max_instructions, builder.timeout, builder.max_heap);
         vm
     }
 
+    fn define_error_class(&mut self) {
+        self.interpret(ERROR_CLASS)
+            .expect("Error class should be valid Lox");
+        match self.get_global("Error") {
+            Some(Value::Class(class)) => self.error_class = Some(class),
+            _ => unreachable!("Error class should be defined"),
+        }
+    }
+
+    /// Compiles and runs a script. It can't be called by native functions
+    /// while the VM is running another script.
     pub fn interpret(&mut self, code: &str) -> Result<(), LoxError> {
-        let function = compile(code, &mut self.gc)?;
+        let function = match compile(code, &mut self.gc) {
+            Ok(function) => function,
+            Err(LoxError::CompileError(diagnostics)) => {
+                self.report_diagnostics(&diagnostics, code);
+                return Err(LoxError::CompileError(diagnostics));
+            }
+            Err(error) => return Err(error),
+        };
+        self.execute(function, Some(code))
+    }
+
+    /// Runs a script previously written with `serialize_function`.
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> Result<(), LoxError> {
+        let function = match deserialize_function(bytes, &mut self.gc) {
+            Ok(function) => function,
+            Err(LoxError::BytecodeError(message)) => {
+                self.report(&message);
+                return Err(LoxError::BytecodeError(message));
+            }
+            Err(error) => return Err(error),
+        };
+        self.execute(function, None)
+    }
+
+    /// Runs a compiled script, whose source is used to report errors.
+    fn execute(&mut self, function: GcRef<Function>, source: Option<&str>) -> Result<(), LoxError> {
+        // The frames and stack of the script that called the native belong
+        // to the outer run, which a nested one would overwrite.
+        if self.in_native {
+            let error = RuntimeError::new("Can't run a script from a native function.");
+            return Err(LoxError::RuntimeError(error));
+        }
+        if let Some(session) = self.debug_session.as_mut() {
+            session.restart();
+        }
+        self.budget.start();
         self.push(Value::Function(function));
-        let closure = self.alloc(Closure::new(function));
+        let closure = self.alloc(Closure::new(function, None));
         self.frames.push(CallFrame::new(closure, 0));
-        self.run()
+        let result = self.run();
+        if let Some(profile) = self.profile.as_mut() {
+            profile.finish();
+        }
+        match &result {
+            Err(LoxError::RuntimeError(error)) => {
+                let source = match &error.path {
+                    Some(path) => self.sources.get(path).map(String::as_str),
+                    None => source,
+                };
+                match source.filter(|_| self.show_source) {
+                    Some(source) => {
+                        let error = error.render(source);
+                        self.report(&error)
+                    }
+                    None => self.report(error),
+                }
+            }
+            Err(LoxError::BudgetExceeded(limit)) => self.report(limit),
+            _ => (),
+        }
+        if result.is_err() {
+            self.reset_stack();
+        }
+        result
+    }
+
+    /// Writes an error to the error output. Errors are also returned to the
+    /// caller, so failing to report them isn't fatal.
+    fn report(&mut self, error: &dyn fmt::Display) {
+        let _ = writeln!(self.error_output, "{}", error);
+    }
+
+    fn report_diagnostics(&mut self, diagnostics: &[Diagnostic], source: &str) {
+        for diagnostic in diagnostics {
+            if self.show_source {
+                self.report(&diagnostic.render(source));
+            } else {
+                self.report(diagnostic);
+            }
+        }
+    }
+
+    pub fn get_global<T: FromValue>(&self, name: &str) -> Option<T> {
+        let name = self.gc.find_interned(name)?;
+        let value = *self.globals.get(&name)?;
+        T::from_value(value, &self.gc)
+    }
+
+    pub fn set_global<T: IntoValue>(&mut self, name: &str, value: T) {
+        let name = self.gc.intern(name.to_owned());
+        let value = value.into_value(&mut self.gc);
+        self.globals.insert(name, value);
     }
 
     fn push(&mut self, v: Value) {
@@ -68,18 +417,250 @@ impl Vm {
         self.stack[size - 1 - n] = value;
     }
 
-    fn define_native(&mut self, name: &str, native: NativeFunction) {
+    /// Registers a Rust closure as a global Lox function.
+    pub fn define_native<F>(&mut self, name: &str, arity: Arity, function: F)
+    where
+        F: Fn(&mut Vm, &[Value]) -> Result<Value, RuntimeError> + 'static,
+    {
+        natives::define(&mut self.gc, &mut self.globals, name, arity, function);
         let name = self.gc.intern(name.to_owned());
-        self.globals.insert(name, Value::NativeFunction(native));
+        self.builtins.insert(name, self.globals[&name]);
+    }
+
+    pub fn gc(&self) -> &Gc {
+        &self.gc
+    }
+
+    pub fn gc_mut(&mut self) -> &mut Gc {
+        &mut self.gc
+    }
+
+    /// Attaches a debugger that pauses the execution at breakpoints and
+    /// steps. Execution pauses at the first line of the next script.
+    pub fn set_debugger(&mut self, debugger: Option<Box<dyn Debugger>>) {
+        self.debug_session = debugger.map(DebugSession::new);
+    }
+
+    /// Starts counting the instructions executed and the time spent in every
+    /// function and line, discarding the previous measurements.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    /// Stops profiling and returns the measurements.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    /// Returns the current call stack, innermost frame first.
+    pub fn backtrace(&self) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let closure = self.gc.deref(frame.closure);
+                let function = self.gc.deref(closure.function);
+                let name = self.gc.deref(function.name);
+                TraceFrame {
+                    function: if name.is_empty() {
+                        None
+                    } else {
+                        Some(name.clone())
+                    },
+                    path: self.module_path(closure.module),
+                    line: function.chunk.lines[frame.ip - 1],
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the local variables in scope in a frame of the call stack,
+    /// where `depth` 0 is the innermost frame.
+    pub fn locals(&self, depth: usize) -> Vec<(String, Value)> {
+        let frame = match self.frames.iter().rev().nth(depth) {
+            Some(frame) => frame,
+            None => return Vec::new(),
+        };
+        let offset = frame.ip - 1;
+        let closure = self.gc.deref(frame.closure);
+        let function = self.gc.deref(closure.function);
+        function
+            .locals
+            .iter()
+            .filter(|local| local.start <= offset && offset < local.end)
+            .filter_map(|local| {
+                let value = self.stack.get(frame.slot + local.slot)?;
+                Some((local.name.clone(), *value))
+            })
+            .collect()
+    }
+
+    /// Returns the variables captured by the closure of a frame of the call
+    /// stack, where `depth` 0 is the innermost frame.
+    pub fn upvalues(&self, depth: usize) -> Vec<(String, Value)> {
+        let frame = match self.frames.iter().rev().nth(depth) {
+            Some(frame) => frame,
+            None => return Vec::new(),
+        };
+        let closure = self.gc.deref(frame.closure);
+        let function = self.gc.deref(closure.function);
+        closure
+            .upvalues
+            .iter()
+            .zip(&function.upvalue_names)
+            .map(|(&upvalue, name)| {
+                let upvalue = self.gc.deref(upvalue);
+                let value = match upvalue.closed {
+                    Some(value) => value,
+                    None => self.stack[upvalue.location],
+                };
+                (name.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Returns the global variables of the module running in the innermost
+    /// frame, sorted by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let table = if self.frames.is_empty() {
+            &self.globals
+        } else {
+            self.current_globals()
+        };
+        let mut globals: Vec<(String, Value)> = table
+            .iter()
+            .map(|(&name, &value)| (self.gc.deref(name).clone(), value))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        globals
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    fn runtime_error<T>(&self, msg: &str) -> Result<T, LoxError> {
+        let trace = self.backtrace();
+        let offset = self.current_frame().ip - 1;
+        Err(LoxError::RuntimeError(RuntimeError {
+            message: msg.to_owned(),
+            trace,
+            offset,
+            span: self.current_chunk().spans[offset],
+            path: self.module_path(self.current_closure().module),
+        }))
     }
 
-    fn runtime_error(&self, msg: &str) -> Result<(), LoxError> {
+    fn module_path(&self, module: Option<GcRef<Module>>) -> Option<PathBuf> {
+        module.map(|module| self.gc.deref(module).path.clone())
+    }
+
+    fn profile_instruction(&mut self) {
+        let depth = self.frames.len();
         let frame = self.current_frame();
-        eprintln!("{}", msg);
-        let chunk = self.current_chunk();
-        let line = chunk.lines[frame.ip - 1];
-        eprintln!("[line {}] in script", line);
-        Err(LoxError::RuntimeError)
+        let (closure, ip) = (frame.closure, frame.ip);
+        let line = self.current_chunk().lines[ip];
+        let mut profile = self.profile.take().unwrap();
+        if !profile.is_current(depth, closure) {
+            let Closure {
+                function, module, ..
+            } = *self.gc.deref(closure);
+            if !profile.has_function(function) {
+                let path = self.module_path(module);
+                profile.add_function(function, self.function_label(function), path);
+            }
+            profile.enter(depth, closure, function, ip == 0);
+        }
+        profile.instruction(line);
+        self.profile = Some(profile);
+    }
+
+    /// Name of a function for reports, with the line where it starts to tell
+    /// apart functions with the same name.
+    fn function_label(&self, function: GcRef<Function>) -> String {
+        let function = self.gc.deref(function);
+        let name = self.gc.deref(function.name);
+        if name.is_empty() {
+            "<script>".to_owned()
+        } else {
+            format!("{}:{}", name, function.chunk.lines[0])
+        }
+    }
+
+    /// Gives control to the debugger if execution should pause before the
+    /// next instruction.
+    fn debug_hook(&mut self) -> Result<(), LoxError> {
+        let mut session = self.debug_session.take().unwrap();
+        let depth = self.frames.len();
+        let ip = self.current_frame().ip;
+        let module = self.current_closure().module;
+        let path = module.map(|module| self.gc.deref(module).path.as_path());
+        let line = session.should_pause(depth, ip, &self.current_chunk().lines, path);
+        let command = line.map(|line| {
+            // While paused, the innermost frame points past the instruction it's
+            // about to execute, the same as the callers do.
+            self.current_frame_mut().ip += 1;
+            let path = module.map(|module| self.gc.deref(module).path.as_path());
+            let command = session.debugger.paused(self, path, line);
+            session.resume(command, depth);
+            command
+        });
+        self.debug_session = Some(session);
+        match command {
+            Some(DebugCommand::Stop) => self.runtime_error("Stopped by the debugger."),
+            Some(_) => {
+                self.current_frame_mut().ip -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.truncate_frames(0);
+        self.open_upvalues.clear();
+    }
+
+    /// Pops the frames above `len`, forgetting the modules whose top-level
+    /// code didn't finish running so that they can be imported again.
+    fn truncate_frames(&mut self, len: usize) {
+        while self.frames.len() > len {
+            let frame = self.frames.pop().unwrap();
+            if let Some(module) = frame.module {
+                self.modules.remove(&self.gc.deref(module).path);
+            }
+        }
+    }
+
+    /// Globals of the module running in the current frame.
+    fn current_globals(&self) -> &Table {
+        match self.current_closure().module {
+            Some(module) => &self.gc.deref(module).globals,
+            None => &self.globals,
+        }
+    }
+
+    fn current_globals_mut(&mut self) -> &mut Table {
+        match self.current_closure().module {
+            Some(module) => &mut self.gc.deref_mut(module).globals,
+            None => &mut self.globals,
+        }
+    }
+
+    /// Constant globals of the module running in the current frame.
+    fn current_constants(&self) -> &HashSet<GcRef<String>> {
+        match self.current_closure().module {
+            Some(module) => &self.gc.deref(module).constants,
+            None => &self.constants,
+        }
+    }
+
+    fn current_constants_mut(&mut self) -> &mut HashSet<GcRef<String>> {
+        match self.current_closure().module {
+            Some(module) => &mut self.gc.deref_mut(module).constants,
+            None => &mut self.constants,
+        }
     }
 
     // PERF: Investigate macros for this
@@ -119,6 +700,16 @@ impl Vm {
 
     fn run(&mut self) -> Result<(), LoxError> {
         loop {
+            if self.budget.countdown == 0 {
+                self.check_budget()?;
+            }
+            self.budget.countdown -= 1;
+            if self.debug_session.is_some() {
+                self.debug_hook()?;
+            }
+            if self.profile.is_some() {
+                self.profile_instruction();
+            }
             let instruction = self.current_chunk().code[self.current_frame().ip];
 
             #[cfg(feature = "debug_trace_execution")]
@@ -128,261 +719,596 @@ impl Vm {
                     self.current_chunk(),
                     Some(&self.stack),
                 );
-                dis.instruction(&instruction, self.current_frame().ip);
+                print!("{}", dis.instruction(&instruction, self.current_frame().ip));
             }
 
             self.current_frame_mut().ip += 1;
 
-            match instruction {
-                Instruction::Add => {
-                    let (b, a) = (self.pop(), self.pop());
-                    match (&a, &b) {
-                        (Value::Number(a), Value::Number(b)) => {
-                            self.push(Value::Number(a + b));
-                        }
+            if let Err(error) = self.run_instruction(instruction) {
+                self.catch(error)?;
+            }
+            if self.frames.is_empty() {
+                return Ok(());
+            }
+        }
+    }
 
-                        (Value::String(a), Value::String(b)) => {
-                            let a = self.gc.deref(*a);
-                            let b = self.gc.deref(*b);
-                            let result = format!("{}{}", a, b);
-                            let result = self.intern(result);
-                            let value = Value::String(result);
-                            self.push(value);
-                        }
+    /// Executes a single instruction. Errors are returned to `run`, which
+    /// turns them into exceptions in one place.
+    #[inline(always)]
+    fn run_instruction(&mut self, instruction: Instruction) -> Result<(), LoxError> {
+        match instruction {
+            Instruction::Add => {
+                let (b, a) = (self.pop(), self.pop());
+                match (&a, &b) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        self.push(Value::Number(a + b));
+                    }
 
-                        _ => {
-                            self.push(a);
-                            self.push(b);
-                            return self
-                                .runtime_error("Operands must be two numbers or two strings.");
-                        }
+                    (Value::String(a), Value::String(b)) => {
+                        let a = self.gc.deref(*a);
+                        let b = self.gc.deref(*b);
+                        let result = format!("{}{}", a, b);
+                        let result = self.intern(result);
+                        let value = Value::String(result);
+                        self.push(value);
+                    }
+
+                    _ => {
+                        self.push(a);
+                        self.push(b);
+                        return self.runtime_error("Operands must be two numbers or two strings.");
                     }
                 }
-                Instruction::Class(constant) => {
-                    let class_name = self.current_chunk().read_string(constant);
-                    let class = Class::new(class_name);
-                    let class = self.alloc(class);
-                    self.push(Value::Class(class));
+            }
+            Instruction::BuildList(count) => {
+                let start = self.stack.len() - count as usize;
+                let items = self.stack[start..].to_vec();
+                let list = self.alloc(List::new(items));
+                self.stack.truncate(start);
+                self.push(Value::List(list));
+            }
+            Instruction::BuildMap(count) => {
+                let start = self.stack.len() - 2 * count as usize;
+                let mut entries = ValueTable::with_capacity(count as usize);
+                for i in (start..self.stack.len()).step_by(2) {
+                    let key = self.map_key(self.stack[i])?;
+                    entries.insert(key, self.stack[i + 1]);
                 }
-                Instruction::CloseUpvalue => {
-                    let stack_top = self.stack.len() - 1;
-                    self.close_upvalues(stack_top);
-                    self.pop();
+                let map = self.alloc(Map::new(entries));
+                self.stack.truncate(start);
+                self.push(Value::Map(map));
+            }
+            Instruction::BuildString(count) => {
+                let start = self.stack.len() - count as usize;
+                let mut result = String::new();
+                for &value in &self.stack[start..] {
+                    let formatter = GcTraceFormatter::new(value, &self.gc);
+                    write!(result, "{}", formatter).unwrap();
                 }
-                Instruction::Closure(constant) => {
-                    let function = self.current_chunk().read_constant(constant);
-                    if let Value::Function(function) = function {
-                        let upvalue_count = self.gc.deref(function).upvalues.len();
-                        let mut closure = Closure::new(function);
-
-                        for i in 0..upvalue_count {
-                            let upvalue = self.gc.deref(function).upvalues[i];
-                            let obj_upvalue = if upvalue.is_local {
-                                let location = self.current_frame().slot + upvalue.index as usize;
-                                self.capture_upvalue(location)
-                            } else {
-                                self.current_closure().upvalues[upvalue.index as usize]
-                            };
-                            closure.upvalues.push(obj_upvalue)
-                        }
+                let result = self.intern(result);
+                self.stack.truncate(start);
+                self.push(Value::String(result));
+            }
+            Instruction::Class(constant) => {
+                let class_name = self.current_chunk().read_string(constant);
+                let class = Class::new(class_name);
+                let class = self.alloc(class);
+                self.push(Value::Class(class));
+            }
+            Instruction::CloseUpvalue => {
+                let stack_top = self.stack.len() - 1;
+                self.close_upvalues(stack_top);
+                self.pop();
+            }
+            Instruction::Closure(constant) => {
+                let function = self.current_chunk().read_constant(constant);
+                if let Value::Function(function) = function {
+                    let upvalue_count = self.gc.deref(function).upvalues.len();
+                    let module = self.current_closure().module;
+                    let mut closure = Closure::new(function, module);
 
-                        let closure = self.alloc(closure);
-                        self.push(Value::Closure(closure));
-                    } else {
-                        panic!("Closure instruction without function value");
+                    for i in 0..upvalue_count {
+                        let upvalue = self.gc.deref(function).upvalues[i];
+                        let obj_upvalue = if upvalue.is_local {
+                            let location = self.current_frame().slot + upvalue.index as usize;
+                            self.capture_upvalue(location)
+                        } else {
+                            self.current_closure().upvalues[upvalue.index as usize]
+                        };
+                        closure.upvalues.push(obj_upvalue)
                     }
+
+                    let closure = self.alloc(closure);
+                    self.push(Value::Closure(closure));
+                } else {
+                    panic!("Closure instruction without function value");
                 }
-                Instruction::Call(arg_count) => {
-                    self.call_value(arg_count as usize)?;
+            }
+            Instruction::Call(arg_count) => {
+                self.call_value(arg_count as usize)?;
+            }
+            Instruction::Constant(constant) => {
+                let value = self.current_chunk().read_constant(constant);
+                self.push(value);
+            }
+            Instruction::DefineConstGlobal(constant) | Instruction::DefineGlobal(constant) => {
+                let global_name = self.current_chunk().read_string(constant);
+                if self.current_constants().contains(&global_name) {
+                    let s = self.gc.deref(global_name);
+                    let msg = format!("Can't redefine constant '{}'.", s);
+                    return self.runtime_error(&msg);
                 }
-                Instruction::Constant(constant) => {
-                    let value = self.current_chunk().read_constant(constant);
-                    self.push(value);
+                let value = self.pop();
+                self.current_globals_mut().insert(global_name, value);
+                if let Instruction::DefineConstGlobal(_) = instruction {
+                    self.current_constants_mut().insert(global_name);
                 }
-                Instruction::DefineGlobal(constant) => {
-                    let global_name = self.current_chunk().read_string(constant);
-                    let value = self.pop();
-                    self.globals.insert(global_name, value);
+            }
+            Instruction::Divide => self.binary_op(|a, b| a / b, Value::Number)?,
+            Instruction::Equal => {
+                let a = self.pop();
+                let b = self.pop();
+                self.push(Value::Bool(a == b));
+            }
+            Instruction::Export(constant) => {
+                let name = self.current_chunk().read_string(constant);
+                if let Some(module) = self.current_closure().module {
+                    self.gc.deref_mut(module).exports.insert(name);
                 }
-                Instruction::Divide => self.binary_op(|a, b| a / b, Value::Number)?,
-                Instruction::Equal => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(Value::Bool(a == b));
+            }
+            Instruction::False => self.push(Value::Bool(false)),
+            Instruction::FloorDivide => self.binary_op(|a, b| (a / b).floor(), Value::Number)?,
+            Instruction::GetGlobal(constant) => {
+                let global_name = self.current_chunk().read_string(constant);
+                match self.current_globals().get(&global_name) {
+                    Some(&value) => self.push(value),
+                    None => {
+                        let global_name = self.gc.deref(global_name);
+                        let msg = format!("Undefined variable '{}'.", global_name);
+                        return self.runtime_error(&msg);
+                    }
                 }
-                Instruction::False => self.push(Value::Bool(false)),
-                Instruction::GetGlobal(constant) => {
-                    let global_name = self.current_chunk().read_string(constant);
-                    match self.globals.get(&global_name) {
-                        Some(&value) => self.push(value),
+            }
+            Instruction::GetIndex => self.get_index()?,
+            Instruction::GetLocal(slot) => {
+                let i = slot as usize + self.current_frame().slot;
+                let value = self.stack[i];
+                self.push(value);
+            }
+            Instruction::GetProperty(constant) => {
+                if let Value::Instance(instance) = self.peek(0) {
+                    let instance = self.gc.deref(instance);
+                    let class = instance.class;
+                    let property_name = self.current_chunk().read_string(constant);
+                    let value = instance.fields.get(&property_name);
+                    match value {
+                        Some(&value) => {
+                            self.pop();
+                            self.push(value);
+                        }
                         None => {
-                            let global_name = self.gc.deref(global_name);
-                            let msg = format!("Undefined variable '{}'.", global_name);
-                            return self.runtime_error(&msg);
+                            self.bind_method(class, property_name)?;
                         }
                     }
-                }
-                Instruction::GetLocal(slot) => {
-                    let i = slot as usize + self.current_frame().slot;
-                    let value = self.stack[i];
+                } else if let Value::Module(module) = self.peek(0) {
+                    let name = self.current_chunk().read_string(constant);
+                    let value = self.module_export(module, name)?;
+                    self.pop();
                     self.push(value);
+                } else {
+                    return self.runtime_error("Only instances have properties.");
                 }
-                Instruction::GetProperty(constant) => {
-                    if let Value::Instance(instance) = self.peek(0) {
-                        let instance = self.gc.deref(instance);
-                        let class = instance.class;
-                        let property_name = self.current_chunk().read_string(constant);
-                        let value = instance.fields.get(&property_name);
-                        match value {
-                            Some(&value) => {
-                                self.pop();
-                                self.push(value);
-                            }
-                            None => {
-                                self.bind_method(class, property_name)?;
-                            }
-                        }
-                    } else {
-                        return self.runtime_error("Only instances have properties.");
-                    }
-                }
-                Instruction::GetSuper(constant) => {
-                    let method_name = self.current_chunk().read_string(constant);
-                    if let Value::Class(superclass) = self.pop() {
-                        self.bind_method(superclass, method_name)?
-                    } else {
-                        panic!("super found no class");
-                    }
-                }
-                Instruction::GetUpvalue(slot) => {
-                    let value = {
-                        let upvalue = self.current_closure().upvalues[slot as usize];
-                        let upvalue = self.gc.deref(upvalue);
-                        if let Some(value) = upvalue.closed {
-                            value
-                        } else {
-                            self.stack[upvalue.location]
-                        }
-                    };
-                    self.push(value);
+            }
+            Instruction::GetSuper(constant) => {
+                let method_name = self.current_chunk().read_string(constant);
+                if let Value::Class(superclass) = self.pop() {
+                    self.bind_method(superclass, method_name)?
+                } else {
+                    return self.runtime_error("Superclass must be a class.");
                 }
-                Instruction::Greater => self.binary_op(|a, b| a > b, Value::Bool)?,
-                Instruction::Inherit => {
-                    let pair = (self.peek(0), self.peek(1));
-                    if let (Value::Class(subclass), Value::Class(superclass)) = pair {
-                        let superclass = self.gc.deref(superclass);
-                        let methods = superclass.methods.clone();
-                        let mut subclass = self.gc.deref_mut(subclass);
-                        subclass.methods = methods;
-                        self.pop();
+            }
+            Instruction::GetUpvalue(slot) => {
+                let value = {
+                    let upvalue = self.current_closure().upvalues[slot as usize];
+                    let upvalue = self.gc.deref(upvalue);
+                    if let Some(value) = upvalue.closed {
+                        value
                     } else {
-                        return self.runtime_error("Superclass must be a class.");
+                        self.stack[upvalue.location]
                     }
+                };
+                self.push(value);
+            }
+            Instruction::Greater => self.binary_op(|a, b| a > b, Value::Bool)?,
+            Instruction::Import(constant) => {
+                let name = self.current_chunk().read_string(constant);
+                self.import(name)?;
+            }
+            Instruction::Inherit => {
+                let pair = (self.peek(0), self.peek(1));
+                if let (Value::Class(subclass), Value::Class(superclass)) = pair {
+                    let superclass = self.gc.deref(superclass);
+                    let methods = superclass.methods.clone();
+                    let subclass = self.gc.deref_mut(subclass);
+                    subclass.methods = methods;
+                    self.pop();
+                } else {
+                    return self.runtime_error("Superclass must be a class.");
                 }
-                Instruction::Invoke((constant, arg_count)) => {
-                    let name = self.current_chunk().read_string(constant);
-                    self.invoke(name, arg_count as usize)?;
-                }
-                Instruction::Jump(offset) => {
+            }
+            Instruction::Invoke((constant, arg_count)) => {
+                let name = self.current_chunk().read_string(constant);
+                self.invoke(name, arg_count as usize)?;
+            }
+            Instruction::Jump(offset) => {
+                self.current_frame_mut().ip += offset as usize;
+            }
+            Instruction::JumpIfFalse(offset) => {
+                if self.peek(0).is_falsey() {
                     self.current_frame_mut().ip += offset as usize;
                 }
-                Instruction::JumpIfFalse(offset) => {
-                    if self.peek(0).is_falsey() {
-                        self.current_frame_mut().ip += offset as usize;
+            }
+            Instruction::Less => self.binary_op(|a, b| a < b, Value::Bool)?,
+            Instruction::Loop(offset) => {
+                self.current_frame_mut().ip -= offset as usize + 1;
+            }
+            Instruction::Method(constant) => {
+                let method_name = self.current_chunk().read_string(constant);
+                self.define_method(method_name)?;
+            }
+            Instruction::Multiply => self.binary_op(|a, b| a * b, Value::Number)?,
+            // The result has the sign of the divisor, consistent with
+            // rounding down in floor division.
+            Instruction::Modulo => self.binary_op(|a, b| a - b * (a / b).floor(), Value::Number)?,
+            Instruction::Negate => {
+                if let Value::Number(value) = self.peek(0) {
+                    self.pop();
+                    self.push(Value::Number(-value));
+                } else {
+                    return self.runtime_error("Operand must be a number.");
+                }
+            }
+            Instruction::Nil => self.push(Value::Nil),
+            Instruction::Not => {
+                let value = self.pop();
+                self.push(Value::Bool(value.is_falsey()));
+            }
+            Instruction::Pop => {
+                self.pop();
+            }
+            Instruction::Power => self.binary_op(f64::powf, Value::Number)?,
+            Instruction::Print => {
+                let value = self.pop();
+                if self.print {
+                    let formatter = GcTraceFormatter::new(value, &self.gc);
+                    if writeln!(self.output, "{}", formatter).is_err() {
+                        return self.runtime_error("Can't write output.");
                     }
                 }
-                Instruction::Less => self.binary_op(|a, b| a < b, Value::Bool)?,
-                Instruction::Loop(offset) => {
-                    self.current_frame_mut().ip -= offset as usize + 1;
+            }
+            Instruction::Return => {
+                let frame = self.frames.pop().unwrap();
+                let return_value = self.pop();
+                self.close_upvalues(frame.slot);
+                self.stack.truncate(frame.slot);
+                if let Some(module) = frame.module {
+                    // Importing a module evaluates to the module itself.
+                    self.gc.deref_mut(module).loaded = true;
+                    self.push(Value::Module(module));
+                } else if !self.frames.is_empty() {
+                    self.push(return_value);
                 }
-                Instruction::Method(constant) => {
-                    let method_name = self.current_chunk().read_string(constant);
-                    self.define_method(method_name);
+            }
+            Instruction::SetGlobal(constant) => {
+                let global_name = self.current_chunk().read_string(constant);
+                if self.current_constants().contains(&global_name) {
+                    let s = self.gc.deref(global_name);
+                    let msg = format!("Can't assign to constant '{}'.", s);
+                    return self.runtime_error(&msg);
                 }
-                Instruction::Multiply => self.binary_op(|a, b| a * b, Value::Number)?,
-                Instruction::Negate => {
-                    if let Value::Number(value) = self.peek(0) {
-                        self.pop();
-                        self.push(Value::Number(-value));
-                    } else {
-                        return self.runtime_error("Operand must be a number.");
-                    }
+                let value = self.peek(0);
+                let globals = self.current_globals_mut();
+                if globals.insert(global_name, value).is_none() {
+                    globals.remove(&global_name);
+                    let s = self.gc.deref(global_name);
+                    let msg = format!("Undefined variable '{}'.", s);
+                    return self.runtime_error(&msg);
                 }
-                Instruction::Nil => self.push(Value::Nil),
-                Instruction::Not => {
+            }
+            Instruction::SetIndex => self.set_index()?,
+            Instruction::SetLocal(slot) => {
+                let i = slot as usize + self.current_frame().slot;
+                let value = self.peek(0);
+                self.stack[i] = value;
+            }
+            Instruction::SetProperty(constant) => {
+                if let Value::Instance(instance) = self.peek(1) {
+                    let property_name = self.current_chunk().read_string(constant);
                     let value = self.pop();
-                    self.push(Value::Bool(value.is_falsey()));
-                }
-                Instruction::Pop => {
+                    self.gc
+                        .deref_mut(instance)
+                        .fields
+                        .insert(property_name, value);
+                    self.resized(instance);
                     self.pop();
+                    self.push(value);
+                } else {
+                    return self.runtime_error("Only instances have fields.");
                 }
-                Instruction::Print => {
-                    let value = self.pop();
-                    let formatter = GcTraceFormatter::new(value, &self.gc);
-                    println!("{}", formatter);
+            }
+            Instruction::SetUpvalue(slot) => {
+                let upvalue = self.current_closure().upvalues[slot as usize];
+                let value = self.peek(0);
+                let upvalue = self.gc.deref_mut(upvalue);
+                if upvalue.closed.is_none() {
+                    self.stack[upvalue.location] = value;
+                } else {
+                    upvalue.closed = Some(value);
+                }
+            }
+            Instruction::Substract => self.binary_op(|a, b| a - b, Value::Number)?,
+            Instruction::SuperInvoke((constant, arg_count)) => {
+                let method_name = self.current_chunk().read_string(constant);
+                if let Value::Class(class) = self.pop() {
+                    self.invoke_from_class(class, method_name, arg_count as usize)?;
+                } else {
+                    return self.runtime_error("Superclass must be a class.");
                 }
-                Instruction::Return => {
-                    let frame = self.frames.pop().unwrap();
-                    let return_value = self.pop();
-                    self.close_upvalues(frame.slot);
+            }
+            Instruction::Throw => return self.throw(),
+            Instruction::True => self.push(Value::Bool(true)),
+        }
+        Ok(())
+    }
 
-                    if self.frames.is_empty() {
-                        return Ok(());
-                    } else {
-                        self.stack.truncate(frame.slot);
-                        self.push(return_value);
-                    }
+    /// Throws the value on top of the stack. The error is only reported if no
+    /// handler catches the value.
+    fn throw(&mut self) -> Result<(), LoxError> {
+        let exception = self.peek(0);
+        if let Value::Instance(instance) = exception {
+            let stack = self.gc.deref(instance).fields.get(&self.stack_string);
+            if stack == Some(&Value::Nil) {
+                let trace = self.trace_string(&self.backtrace());
+                let stack_string = self.stack_string;
+                let instance = self.gc.deref_mut(instance);
+                instance.fields.insert(stack_string, Value::String(trace));
+            }
+        }
+        let message = match exception {
+            Value::Instance(instance) => self.gc.deref(instance).fields.get(&self.message_string),
+            _ => None,
+        };
+        let message = match message {
+            Some(&message) => GcTraceFormatter::new(message, &self.gc).to_string(),
+            None => {
+                let exception = GcTraceFormatter::new(exception, &self.gc);
+                format!("Uncaught exception '{}'.", exception)
+            }
+        };
+        self.pop();
+        self.exception = Some(exception);
+        self.runtime_error(&message)
+    }
+
+    /// Unwinds the stack to the innermost handler of a runtime error, which
+    /// receives the thrown value or an `Error` instance describing the error.
+    /// Errors without handler, and errors that can't be caught, such as
+    /// exceeding the budget, are returned.
+    fn catch(&mut self, error: LoxError) -> Result<(), LoxError> {
+        let error = match error {
+            LoxError::RuntimeError(error) => error,
+            error => return Err(error),
+        };
+        let exception = match self.exception.take() {
+            Some(exception) => exception,
+            None => self.error_instance(&error),
+        };
+        for depth in (0..self.frames.len()).rev() {
+            let frame = &self.frames[depth];
+            let closure = self.gc.deref(frame.closure);
+            let chunk = &self.gc.deref(closure.function).chunk;
+            if let Some(handler) = chunk.find_handler(frame.ip - 1) {
+                let slot = frame.slot + handler.depth;
+                self.truncate_frames(depth + 1);
+                self.close_upvalues(slot);
+                self.stack.truncate(slot);
+                self.push(exception);
+                self.current_frame_mut().ip = handler.target;
+                return Ok(());
+            }
+        }
+        Err(LoxError::RuntimeError(error))
+    }
+
+    fn error_instance(&mut self, error: &RuntimeError) -> Value {
+        let message = self.intern(error.message.clone());
+        self.push(Value::String(message));
+        let stack = self.trace_string(&error.trace);
+        self.push(Value::String(stack));
+        let mut instance = Instance::new(self.error_class.unwrap());
+        instance
+            .fields
+            .insert(self.message_string, Value::String(message));
+        instance
+            .fields
+            .insert(self.stack_string, Value::String(stack));
+        let instance = self.alloc(instance);
+        self.pop();
+        self.pop();
+        Value::Instance(instance)
+    }
+
+    fn trace_string(&mut self, trace: &[TraceFrame]) -> GcRef<String> {
+        let lines: Vec<String> = trace.iter().map(|frame| frame.to_string()).collect();
+        self.intern(lines.join("\n"))
+    }
+
+    /// Pushes an imported module. The first time it's imported, the module is
+    /// compiled and its top-level code is called, which leaves the module on
+    /// the stack when it returns.
+    fn import(&mut self, name: GcRef<String>) -> Result<(), LoxError> {
+        let path = self.resolve_module(name)?;
+        if let Some(&module) = self.modules.get(&path) {
+            if !self.gc.deref(module).loaded {
+                let msg = format!("Import cycle with module '{}'.", self.gc.deref(name));
+                return self.runtime_error(&msg);
+            }
+            self.push(Value::Module(module));
+            return Ok(());
+        }
+
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(error) => {
+                let msg = format!("Can't read module '{}': {}.", self.gc.deref(name), error);
+                return self.runtime_error(&msg);
+            }
+        };
+        let function = match compile(&source, &mut self.gc) {
+            Ok(function) => function,
+            Err(LoxError::CompileError(diagnostics)) => {
+                self.report_diagnostics(&diagnostics, &source);
+                let msg = format!("Can't compile module '{}'.", self.gc.deref(name));
+                return self.runtime_error(&msg);
+            }
+            Err(error) => return Err(error),
+        };
+
+        // Both are kept on the stack while allocating, so that they aren't
+        // collected.
+        self.push(Value::Function(function));
+        let module = Module::new(name, path.clone(), self.builtins.clone());
+        let module = self.alloc(module);
+        self.push(Value::Module(module));
+        let closure = self.alloc(Closure::new(function, Some(module)));
+        self.pop();
+        self.pop();
+        self.push(Value::Closure(closure));
+        self.call(closure, 0)?;
+        self.current_frame_mut().module = Some(module);
+        if self.show_source {
+            self.sources.insert(path.clone(), source);
+        }
+        self.modules.insert(path, module);
+        Ok(())
+    }
+
+    /// Finds the file of a module, first relative to the importing module and
+    /// then in the module paths.
+    fn resolve_module(&self, name: GcRef<String>) -> Result<PathBuf, LoxError> {
+        let name = self.gc.deref(name);
+        let importer = self
+            .current_closure()
+            .module
+            .and_then(|module| self.gc.deref(module).path.parent());
+        let path = importer
+            .into_iter()
+            .chain(self.module_paths.iter().map(PathBuf::as_path))
+            .chain(Some(Path::new("")).filter(|_| Path::new(name).is_absolute()))
+            .filter_map(|dir| dir.join(name).canonicalize().ok())
+            .find(|path| path.is_file());
+        let path = match path {
+            Some(path) => path,
+            None => return self.runtime_error(&format!("Can't find module '{}'.", name)),
+        };
+        if !self.can_read(&path) {
+            return self.runtime_error(&format!("Can't import module '{}'.", name));
+        }
+        Ok(path)
+    }
+
+    /// Whether `path` is inside one of the readable roots.
+    pub(crate) fn can_read(&self, path: &Path) -> bool {
+        match &self.readable_roots {
+            // Resolves symlinks and `..` before checking the path.
+            Some(roots) => path
+                .canonicalize()
+                .is_ok_and(|path| roots.iter().any(|root| path.starts_with(root))),
+            None => true,
+        }
+    }
+
+    fn module_export(&self, module: GcRef<Module>, name: GcRef<String>) -> Result<Value, LoxError> {
+        let module = self.gc.deref(module);
+        if module.exports.contains(&name) {
+            if let Some(&value) = module.globals.get(&name) {
+                return Ok(value);
+            }
+        }
+        let msg = format!(
+            "Module '{}' doesn't export '{}'.",
+            self.gc.deref(module.name),
+            self.gc.deref(name)
+        );
+        self.runtime_error(&msg)
+    }
+
+    fn get_index(&mut self) -> Result<(), LoxError> {
+        let (index, target) = (self.peek(0), self.peek(1));
+        let value = match target {
+            Value::List(list) => {
+                let items = &self.gc.deref(list).items;
+                match natives::to_index(index, items.len(), false) {
+                    Ok(i) => items[i],
+                    Err(error) => return self.runtime_error(&error.message),
                 }
-                Instruction::SetGlobal(constant) => {
-                    let global_name = self.current_chunk().read_string(constant);
-                    let value = self.peek(0);
-                    if self.globals.insert(global_name, value).is_none() {
-                        self.globals.remove(&global_name);
-                        let s = self.gc.deref(global_name);
-                        let msg = format!("Undefined variable '{}'.", s);
+            }
+            Value::Map(map) => {
+                let key = self.map_key(index)?;
+                match self.gc.deref(map).entries.get(&key) {
+                    Some(&value) => value,
+                    None => {
+                        let key = GcTraceFormatter::new(index, &self.gc);
+                        let msg = format!("Undefined key '{}'.", key);
                         return self.runtime_error(&msg);
                     }
                 }
-                Instruction::SetLocal(slot) => {
-                    let i = slot as usize + self.current_frame().slot;
-                    let value = self.peek(0);
-                    self.stack[i] = value;
-                }
-                Instruction::SetProperty(constant) => {
-                    if let Value::Instance(instance) = self.peek(1) {
-                        let property_name = self.current_chunk().read_string(constant);
-                        let value = self.pop();
-                        let instance = self.gc.deref_mut(instance);
-                        instance.fields.insert(property_name, value);
-                        self.pop();
-                        self.push(value);
-                    } else {
-                        return self.runtime_error("Only instances have fields.");
-                    }
-                }
-                Instruction::SetUpvalue(slot) => {
-                    let upvalue = self.current_closure().upvalues[slot as usize];
-                    let value = self.peek(0);
-                    let mut upvalue = self.gc.deref_mut(upvalue);
-                    if upvalue.closed.is_none() {
-                        self.stack[upvalue.location] = value;
-                    } else {
-                        upvalue.closed = Some(value);
-                    }
-                }
-                Instruction::Substract => self.binary_op(|a, b| a - b, Value::Number)?,
-                Instruction::SuperInvoke((constant, arg_count)) => {
-                    let method_name = self.current_chunk().read_string(constant);
-                    if let Value::Class(class) = self.pop() {
-                        self.invoke_from_class(class, method_name, arg_count as usize)?;
-                    } else {
-                        panic!("super invoke with no class");
-                    }
+            }
+            Value::String(string) => match natives::char_at(self.gc.deref::<String>(string), index)
+            {
+                Ok(c) => Value::String(self.intern(c)),
+                Err(error) => return self.runtime_error(&error.message),
+            },
+            _ => return self.runtime_error("Can only index lists, maps and strings."),
+        };
+        self.pop();
+        self.pop();
+        self.push(value);
+        Ok(())
+    }
+
+    fn set_index(&mut self) -> Result<(), LoxError> {
+        let (value, index, target) = (self.peek(0), self.peek(1), self.peek(2));
+        match target {
+            Value::List(list) => {
+                let items = &mut self.gc.deref_mut(list).items;
+                match natives::to_index(index, items.len(), false) {
+                    Ok(i) => items[i] = value,
+                    Err(error) => return self.runtime_error(&error.message),
                 }
-                Instruction::True => self.push(Value::Bool(true)),
-            };
+            }
+            Value::Map(map) => {
+                let key = self.map_key(index)?;
+                self.gc.deref_mut(map).entries.insert(key, value);
+                self.resized(map);
+            }
+            _ => return self.runtime_error("Can only index lists and maps."),
+        }
+        self.pop();
+        self.pop();
+        self.pop();
+        self.push(value);
+        Ok(())
+    }
+
+    fn map_key(&self, value: Value) -> Result<MapKey, LoxError> {
+        match natives::to_map_key(value) {
+            Ok(key) => Ok(key),
+            Err(error) => self.runtime_error(&error.message),
         }
     }
+
     fn call_value(&mut self, arg_count: usize) -> Result<(), LoxError> {
         let callee = self.peek(arg_count);
         match callee {
@@ -410,14 +1336,44 @@ impl Vm {
                 Ok(())
             }
             Value::Closure(closure) => self.call(closure, arg_count),
-            Value::NativeFunction(native) => {
-                let left = self.stack.len() - arg_count;
-                let result = native.0(self, &self.stack[left..]);
-                self.stack.truncate(left - 1);
+            Value::NativeFunction(native) => self.call_native(native, arg_count, false),
+            _ => self.runtime_error("Can only call functions and classes."),
+        }
+    }
+
+    /// Calls a native function with the arguments on top of the stack. Method
+    /// natives also receive the receiver, stored in the callee slot.
+    fn call_native(
+        &mut self,
+        native: GcRef<NativeFunction>,
+        arg_count: usize,
+        with_receiver: bool,
+    ) -> Result<(), LoxError> {
+        let native = self.gc.deref(native);
+        if let Arity::Fixed(arity) = native.arity {
+            if arity != arg_count {
+                let msg = format!("Expected {} arguments but got {}.", arity, arg_count);
+                return self.runtime_error(&msg);
+            }
+        }
+        let function = Rc::clone(&native.function);
+        let callee = self.stack.len() - arg_count - 1;
+        let left = if with_receiver { callee } else { callee + 1 };
+        let args = self.stack[left..].to_vec();
+
+        // Values allocated by the native are not rooted until it returns, so
+        // collection is postponed until then.
+        self.in_native = true;
+        let result = function(self, &args);
+        self.in_native = false;
+
+        match result {
+            Ok(result) => {
+                self.stack.truncate(callee);
                 self.push(result);
                 Ok(())
             }
-            _ => self.runtime_error("Can only call functions and classes."),
+            Err(error) => self.runtime_error(&error.message),
         }
     }
 
@@ -430,7 +1386,7 @@ impl Vm {
                 function.arity, arg_count
             );
             self.runtime_error(&msg)
-        } else if self.frames.len() == Vm::MAX_FRAMES {
+        } else if self.frames.len() >= self.max_frames || self.stack.len() > self.max_stack {
             self.runtime_error("Stack overflow.")
         } else {
             let frame = CallFrame::new(closure_ref, self.stack.len() - arg_count - 1);
@@ -441,17 +1397,50 @@ impl Vm {
 
     fn invoke(&mut self, name: GcRef<String>, arg_count: usize) -> Result<(), LoxError> {
         let receiver = self.peek(arg_count);
-        if let Value::Instance(instance) = receiver {
-            let instance = self.gc.deref(instance);
-            if let Some(&field) = instance.fields.get(&name) {
-                self.set_at(arg_count, field);
+        match receiver {
+            Value::Instance(instance) => {
+                let instance = self.gc.deref(instance);
+                if let Some(&field) = instance.fields.get(&name) {
+                    self.set_at(arg_count, field);
+                    self.call_value(arg_count)
+                } else {
+                    let class = instance.class;
+                    self.invoke_from_class(class, name, arg_count)
+                }
+            }
+            Value::List(_) => {
+                let method = self.list_methods.get(&name).copied();
+                self.invoke_native_method(method, name, arg_count)
+            }
+            Value::Map(_) => {
+                let method = self.map_methods.get(&name).copied();
+                self.invoke_native_method(method, name, arg_count)
+            }
+            Value::String(_) => {
+                let method = self.string_methods.get(&name).copied();
+                self.invoke_native_method(method, name, arg_count)
+            }
+            Value::Module(module) => {
+                let value = self.module_export(module, name)?;
+                self.set_at(arg_count, value);
                 self.call_value(arg_count)
-            } else {
-                let class = instance.class;
-                self.invoke_from_class(class, name, arg_count)
             }
+            _ => self.runtime_error("Only instances have methods."),
+        }
+    }
+
+    fn invoke_native_method(
+        &mut self,
+        method: Option<Value>,
+        name: GcRef<String>,
+        arg_count: usize,
+    ) -> Result<(), LoxError> {
+        if let Some(Value::NativeFunction(native)) = method {
+            self.call_native(native, arg_count, true)
         } else {
-            self.runtime_error("Only instances have methods.")
+            let name = self.gc.deref(name);
+            let msg = format!("Undefined property '{}'.", name);
+            self.runtime_error(&msg)
         }
     }
 
@@ -523,38 +1512,84 @@ impl Vm {
         }
     }
 
-    fn define_method(&mut self, name: GcRef<String>) {
+    // Only reachable with hand-crafted bytecode, which can put any value
+    // where the compiler puts a class or a method.
+    fn define_method(&mut self, name: GcRef<String>) -> Result<(), LoxError> {
         let method = self.peek(0);
-        if let Value::Class(class) = self.peek(1) {
-            let class = self.gc.deref_mut(class);
-            class.methods.insert(name, method);
-            self.pop();
-        } else {
-            panic!("Invalid state: trying to define a method of non class");
+        match (self.peek(1), method) {
+            (Value::Class(class), Value::Closure(_)) => {
+                let class = self.gc.deref_mut(class);
+                class.methods.insert(name, method);
+                self.pop();
+                Ok(())
+            }
+            (Value::Class(_), _) => self.runtime_error("Methods must be functions."),
+            _ => self.runtime_error("Methods must be defined in a class."),
         }
     }
 
-    fn alloc<T: GcTrace + 'static + Debug>(&mut self, object: T) -> GcRef<T> {
+    pub fn alloc<T: GcTrace + 'static + Debug>(&mut self, object: T) -> GcRef<T> {
         self.mark_and_sweep();
         self.gc.alloc(object)
     }
 
-    fn intern(&mut self, name: String) -> GcRef<String> {
+    /// Accounts for an object that has grown in place, like a list after a
+    /// push. Natives that grow objects should call this so that the growth
+    /// counts against [`VmBuilder::max_heap`].
+    pub fn resized<T: GcTrace + 'static>(&mut self, reference: GcRef<T>) {
+        self.gc.resize(reference);
+        self.mark_and_sweep();
+    }
+
+    pub fn intern(&mut self, name: String) -> GcRef<String> {
         self.mark_and_sweep();
         self.gc.intern(name)
     }
 
     fn mark_and_sweep(&mut self) {
-        if self.gc.should_gc() {
-            #[cfg(feature = "debug_log_gc")]
-            println!("-- gc begin");
+        if self.gc.should_gc() && !self.in_native {
+            self.collect_garbage();
+        }
+        if self.gc.bytes_allocated() > self.budget.max_heap {
+            // Checked before the next instruction, when it's safe to collect.
+            self.budget.interrupt();
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        #[cfg(feature = "debug_log_gc")]
+        println!("-- gc begin");
 
-            self.mark_roots();
-            self.gc.collect_garbage();
+        self.mark_roots();
+        self.gc.collect_garbage();
 
-            #[cfg(feature = "debug_log_gc")]
-            println!("-- gc end");
+        #[cfg(feature = "debug_log_gc")]
+        println!("-- gc end");
+    }
+
+    /// Checks the execution budget at the end of an instruction slice, or
+    /// when the heap grows past its limit.
+    fn check_budget(&mut self) -> Result<(), LoxError> {
+        if self.gc.bytes_allocated() > self.budget.max_heap {
+            self.collect_garbage();
+            if self.gc.bytes_allocated() > self.budget.max_heap {
+                return Err(LoxError::BudgetExceeded(Limit::Memory));
+            }
         }
+        if self
+            .budget
+            .deadline
+            .is_some_and(|deadline| Instant::now() > deadline)
+        {
+            return Err(LoxError::BudgetExceeded(Limit::Time));
+        }
+        if self.budget.instructions_left == 0 {
+            return Err(LoxError::BudgetExceeded(Limit::Instructions));
+        }
+        let slice = self.budget.instructions_left.min(Budget::SLICE);
+        self.budget.instructions_left -= slice;
+        self.budget.countdown = slice;
+        Ok(())
     }
 
     fn mark_roots(&mut self) {
@@ -571,7 +1606,78 @@ impl Vm {
         }
 
         self.gc.mark_table(&self.globals);
+        self.gc.mark_table(&self.builtins);
+        for &name in &self.constants {
+            self.gc.mark_object(name);
+        }
+        for &module in self.modules.values() {
+            self.gc.mark_object(module);
+        }
+        self.gc.mark_table(&self.list_methods);
+        self.gc.mark_table(&self.map_methods);
+        self.gc.mark_table(&self.string_methods);
         self.gc.mark_object(self.init_string);
+        self.gc.mark_object(self.message_string);
+        self.gc.mark_object(self.stack_string);
+        if let Some(class) = self.error_class {
+            self.gc.mark_object(class);
+        }
+        if let Some(exception) = self.exception {
+            self.gc.mark_value(exception);
+        }
+        if let Some(profile) = &self.profile {
+            profile.trace(&mut self.gc);
+        }
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Limits of a single script run. Instructions are counted down in slices,
+/// so that the other limits only need to be checked between them.
+struct Budget {
+    max_instructions: Option<u64>,
+    timeout: Option<Duration>,
+    max_heap: usize,
+    /// Instructions left after the current slice.
+    instructions_left: u64,
+    /// Instructions left in the current slice.
+    countdown: u64,
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    const SLICE: u64 = 1024;
+
+    fn new(
+        max_instructions: Option<u64>,
+        timeout: Option<Duration>,
+        max_heap: Option<usize>,
+    ) -> Self {
+        Budget {
+            max_instructions,
+            timeout,
+            max_heap: max_heap.unwrap_or(usize::MAX),
+            instructions_left: 0,
+            countdown: 0,
+            deadline: None,
+        }
+    }
+
+    fn start(&mut self) {
+        self.instructions_left = self.max_instructions.unwrap_or(u64::MAX);
+        self.countdown = 0;
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Ends the current slice early.
+    fn interrupt(&mut self) {
+        self.instructions_left += self.countdown;
+        self.countdown = 0;
     }
 }
 
@@ -579,6 +1685,8 @@ struct CallFrame {
     closure: GcRef<Closure>,
     ip: usize,
     slot: usize,
+    /// Module being imported, if the frame runs its top-level code.
+    module: Option<GcRef<Module>>,
 }
 
 impl CallFrame {
@@ -587,23 +1695,7 @@ impl CallFrame {
             closure,
             ip: 0,
             slot,
+            module: None,
         }
     }
 }
-
-fn clock(vm: &Vm, _args: &[Value]) -> Value {
-    let time = vm.start_time.elapsed().as_secs_f64();
-    Value::Number(time)
-}
-
-fn lox_panic(vm: &Vm, args: &[Value]) -> Value {
-    let mut terms: Vec<String> = vec![];
-
-    for &arg in args.iter() {
-        let formatter = GcTraceFormatter::new(arg, &vm.gc);
-        let term = format!("{}", formatter);
-        terms.push(term);
-    }
-
-    panic!("panic: {}", terms.join(", "))
-}
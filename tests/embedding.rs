@@ -0,0 +1,1109 @@
+use loxido::{
+    compile, disassemble_function, is_bytecode, serialize_function, DebugCommand, Debugger,
+    Diagnostic, Gc, GcTraceFormatter, Limit, LoxError, NativeModule, Severity, Span, TraceFrame,
+    Value, Vm, VmConfig,
+};
+use std::{
+    cell::RefCell,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+
+#[test]
+fn interpret_and_read_globals() {
+    let mut vm = Vm::new();
+    vm.interpret("var answer = 6 * 7; var name = \"lox\"; var ok = answer == 42;")
+        .unwrap();
+
+    assert_eq!(vm.get_global::<f64>("answer"), Some(42.0));
+    assert_eq!(vm.get_global::<String>("name"), Some("lox".to_owned()));
+    assert_eq!(vm.get_global::<bool>("ok"), Some(true));
+    assert_eq!(vm.get_global::<bool>("answer"), None);
+    assert_eq!(vm.get_global::<f64>("missing"), None);
+}
+
+#[test]
+fn write_globals_from_host() {
+    let mut vm = Vm::new();
+    vm.set_global("base", 10.0);
+    vm.set_global("greeting", "hello");
+    vm.interpret("var result = base * 2; var message = greeting + \" world\";")
+        .unwrap();
+
+    assert_eq!(vm.get_global::<f64>("result"), Some(20.0));
+    assert_eq!(
+        vm.get_global::<String>("message"),
+        Some("hello world".to_owned())
+    );
+
+    vm.set_global("base", ());
+    assert_eq!(vm.get_global::<Value>("base"), Some(Value::Nil));
+}
+
+#[test]
+fn interpret_reports_errors() {
+    let mut vm = Vm::new();
+    assert!(matches!(
+        vm.interpret("var = 1;"),
+        Err(LoxError::CompileError(_))
+    ));
+    assert!(matches!(
+        vm.interpret("-\"not a number\";"),
+        Err(LoxError::RuntimeError(_))
+    ));
+}
+
+#[test]
+fn run_several_scripts_with_locals() {
+    let mut vm = Vm::new();
+    for i in 0..3 {
+        vm.interpret("var result; { var a = 1; var b = 2; result = a + b; }")
+            .unwrap();
+        assert_eq!(vm.get_global::<f64>("result"), Some(3.0), "run {}", i);
+        assert!(vm.stack().is_empty());
+    }
+}
+
+#[test]
+fn constants_are_kept_between_scripts() {
+    let mut vm = Vm::new();
+    vm.interpret("const limit = 10;").unwrap();
+    assert_eq!(
+        runtime_error_message(&mut vm, "limit = 20;"),
+        "Can't assign to constant 'limit'."
+    );
+    assert_eq!(
+        runtime_error_message(&mut vm, "var limit = 20;"),
+        "Can't redefine constant 'limit'."
+    );
+    assert_eq!(vm.get_global::<f64>("limit"), Some(10.0));
+}
+
+#[test]
+fn runtime_error_stack_trace() {
+    let mut vm = Vm::new();
+    let source = "fun inner() {\n  return nil * 1;\n}\nfun outer() {\n  inner();\n}\nouter();\n";
+    let error = match vm.interpret(source) {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+
+    assert_eq!(error.message, "Operands must be numbers.");
+    assert_eq!(
+        error.trace,
+        vec![
+            TraceFrame {
+                function: Some("inner".to_owned()),
+                path: None,
+                line: 2
+            },
+            TraceFrame {
+                function: Some("outer".to_owned()),
+                path: None,
+                line: 5
+            },
+            TraceFrame {
+                function: None,
+                path: None,
+                line: 7
+            },
+        ]
+    );
+    assert_eq!(
+        error.to_string(),
+        "Operands must be numbers.\n[line 2] in inner()\n[line 5] in outer()\n[line 7] in script"
+    );
+
+    vm.interpret("var recovered = true;").unwrap();
+    assert_eq!(vm.get_global::<bool>("recovered"), Some(true));
+}
+
+#[test]
+fn compile_errors_are_collected() {
+    let mut vm = Vm::new();
+    let diagnostics = match vm.interpret("var a = 1;\n  var = 2;\nprint a;\nprint (1;") {
+        Err(LoxError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("Expected compile error"),
+    };
+
+    assert_eq!(
+        diagnostics,
+        vec![
+            Diagnostic {
+                severity: Severity::Error,
+                message: "Expect variable name.".to_owned(),
+                line: 2,
+                column: 7,
+                span: Span::new(17, 18),
+                lexeme: Some("=".to_owned()),
+            },
+            Diagnostic {
+                severity: Severity::Error,
+                message: "Expect ')' after expression.".to_owned(),
+                line: 4,
+                column: 9,
+                span: Span::new(39, 40),
+                lexeme: Some(";".to_owned()),
+            },
+        ]
+    );
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 2] Error at '=': Expect variable name."
+    );
+}
+
+#[test]
+fn errors_render_source_snippets() {
+    let mut vm = Vm::new();
+    let source = "var a = 1;\nvar b = \"two\";\nprint a * b;\n";
+    let error = match vm.interpret(source) {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+    assert_eq!(
+        error.render(source),
+        "Operands must be numbers.\n3 | print a * b;\n  |       ^^^^^\n[line 3] in script"
+    );
+
+    let source = "print 1 +;";
+    let diagnostics = match vm.interpret(source) {
+        Err(LoxError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("Expected compile error"),
+    };
+    assert_eq!(
+        diagnostics[0].render(source),
+        "[line 1] Error at ';': Expect expression.\n1 | print 1 +;\n  |          ^"
+    );
+
+    let source = "print \"héllo\" * 2;";
+    let error = match vm.interpret(source) {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+    assert_eq!(
+        error.render(source),
+        "Operands must be numbers.\n1 | print \"héllo\" * 2;\n  |       ^^^^^^^^^^^\n[line 1] in script"
+    );
+}
+
+#[test]
+fn errors_in_modules_know_their_source() {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("error_module");
+    fs::create_dir_all(&root).unwrap();
+    let module_source = "export fun boom() {\n  nil();\n}\n";
+    fs::write(root.join("boom.lox"), module_source).unwrap();
+    let module = root.join("boom.lox").canonicalize().unwrap();
+    let source = "\
+var s = \"ééééééééééééééééé\";
+import { boom } from \"boom.lox\";
+boom();
+";
+    let config = VmConfig {
+        module_paths: vec![root.clone()],
+        ..VmConfig::trusted()
+    };
+    let mut vm = Vm::builder().config(config).build();
+    let error = match vm.interpret(source) {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+
+    assert_eq!(error.path.as_deref(), Some(module.as_path()));
+    assert_eq!(error.trace[0].path.as_deref(), Some(module.as_path()));
+    assert_eq!(error.trace[1].path, None);
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "Can only call functions and classes.\n[{} line 2] in boom()\n[line 3] in script",
+            module.display()
+        )
+    );
+    assert!(error
+        .render(module_source)
+        .contains("2 |   nil();\n  |   ^^^^^"));
+    // Rendering against the wrong source doesn't panic.
+    error.render(source);
+}
+
+#[test]
+fn snippets_clamp_spans_to_the_source() {
+    // Past the end, and ending inside a multi-byte character.
+    assert_eq!(
+        Span::new(40, 50).snippet("print 1;"),
+        "1 | print 1;\n  |         ^"
+    );
+    assert_eq!(Span::new(3, 8).snippet("é é é"), "1 | é é é\n  |   ^^^");
+    assert_eq!(Span::new(1, 2).snippet("é"), "1 | é\n  | ^");
+}
+
+#[test]
+fn native_closures() {
+    use loxido::{Arity, RuntimeError};
+    use std::{cell::Cell, rc::Rc};
+
+    let mut vm = Vm::new();
+    let calls = Rc::new(Cell::new(0));
+    let counter = Rc::clone(&calls);
+    vm.define_native("count", Arity::Fixed(0), move |_vm, _args| {
+        counter.set(counter.get() + 1);
+        Ok(Value::Number(counter.get() as f64))
+    });
+    vm.define_native("join", Arity::Variadic, |vm, args| {
+        let mut result = String::new();
+        for &arg in args {
+            match arg {
+                Value::String(s) => result.push_str(vm.gc().deref::<String>(s)),
+                _ => return Err(RuntimeError::new("Arguments must be strings.")),
+            }
+        }
+        Ok(Value::String(vm.intern(result)))
+    });
+
+    vm.interpret("count(); var n = count(); var s = join(\"a\", \"b\", \"c\");")
+        .unwrap();
+    assert_eq!(calls.get(), 2);
+    assert_eq!(vm.get_global::<f64>("n"), Some(2.0));
+    assert_eq!(vm.get_global::<String>("s"), Some("abc".to_owned()));
+
+    let error = match vm.interpret("fun f() {\n  join(\"a\", 1);\n}\nf();") {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+    assert_eq!(error.message, "Arguments must be strings.");
+    assert_eq!(error.trace.len(), 2);
+    assert_eq!(error.trace[0].line, 2);
+
+    let error = match vm.interpret("count(1);") {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+    assert_eq!(error.message, "Expected 0 arguments but got 1.");
+}
+
+#[test]
+fn natives_cant_interpret() {
+    use loxido::{Arity, RuntimeError};
+
+    let mut vm = Vm::new();
+    vm.define_native("eval", Arity::Fixed(1), |vm, args| {
+        let code = match args[0] {
+            Value::String(s) => vm.gc().deref::<String>(s).clone(),
+            _ => return Err(RuntimeError::new("Argument must be a string.")),
+        };
+        match vm.interpret(&code) {
+            Ok(()) => Ok(Value::Nil),
+            Err(LoxError::RuntimeError(error)) => Err(error),
+            Err(_) => Err(RuntimeError::new("Can't compile.")),
+        }
+    });
+    let source = "fun g() { var a = \"A\"; var r = eval(\"print 1;\"); print a; } g();";
+    assert_eq!(
+        runtime_error_message(&mut vm, source),
+        "Can't run a script from a native function."
+    );
+    vm.interpret("var ok = true;").unwrap();
+    assert_eq!(vm.get_global::<bool>("ok"), Some(true));
+}
+
+#[test]
+fn bytecode_round_trip() {
+    let mut gc = Gc::new();
+    let function = compile(
+        "fun twice(x) { return x * 2; } var result = twice(21);",
+        &mut gc,
+    )
+    .unwrap_or_else(|_| panic!("Expected script to compile"));
+    let bytes = serialize_function(function, &gc);
+    assert!(is_bytecode(&bytes));
+
+    let mut vm = Vm::new();
+    vm.interpret_bytecode(&bytes).unwrap();
+    assert_eq!(vm.get_global::<f64>("result"), Some(42.0));
+
+    let mut other_version = bytes.clone();
+    other_version[4] = 0xff;
+    assert!(matches!(
+        vm.interpret_bytecode(&other_version),
+        Err(LoxError::BytecodeError(_))
+    ));
+    assert!(matches!(
+        vm.interpret_bytecode(&bytes[..bytes.len() - 1]),
+        Err(LoxError::BytecodeError(_))
+    ));
+}
+
+fn tampered_bytecode(source: &str, pattern: &[u8], replacement: &[u8]) -> Vec<u8> {
+    let mut gc = Gc::new();
+    let function =
+        compile(source, &mut gc).unwrap_or_else(|_| panic!("Expected script to compile"));
+    let mut bytes = serialize_function(function, &gc);
+    let positions: Vec<usize> = bytes
+        .windows(pattern.len())
+        .enumerate()
+        .filter(|(_, window)| *window == pattern)
+        .map(|(position, _)| position)
+        .collect();
+    assert_eq!(
+        positions.len(),
+        1,
+        "Expected the pattern once in {:?}",
+        bytes
+    );
+    bytes[positions[0]..positions[0] + pattern.len()].copy_from_slice(replacement);
+    bytes
+}
+
+#[test]
+fn bytecode_rejects_tampered_code() {
+    // Constant 0 is the name "a" and constant 1 is the number.
+    let global = "var a = 1;";
+    let define = [7, 1, 0, 8, 0, 0];
+    let cases = [
+        // A global named by a number.
+        tampered_bytecode(global, &define, &[7, 1, 0, 8, 1, 0]),
+        // A constant that doesn't exist.
+        tampered_bytecode(global, &define, &[7, 9, 0, 8, 0, 0]),
+        // A jump far past the end.
+        tampered_bytecode(global, &define, &[21, 0xff, 0xff, 8, 0, 0]),
+        // A local slot above the stack.
+        tampered_bytecode(global, &define, &[14, 5, 0, 8, 0, 0]),
+        // Popping more values than there are.
+        tampered_bytecode(global, &define, &[8, 0, 0, 8, 0, 0]),
+        // Running off the end of the code.
+        tampered_bytecode(global, &[28, 32], &[28, 28]),
+        // A closure made from a string.
+        tampered_bytecode("fun f() {}", &[6, 1, 0, 8, 0, 0], &[6, 0, 0, 8, 0, 0]),
+        // An upvalue the closure doesn't have.
+        tampered_bytecode(
+            "fun f() { var x; fun g() { return x; } }",
+            &[17, 0, 0],
+            &[17, 1, 0],
+        ),
+    ];
+
+    let mut vm = Vm::new();
+    for bytes in cases {
+        assert!(matches!(
+            vm.interpret_bytecode(&bytes),
+            Err(LoxError::BytecodeError(_))
+        ));
+    }
+}
+
+#[test]
+fn disassemble_nested_functions() {
+    let mut gc = Gc::new();
+    let source = "fun outer() {\n  var x = 1;\n  fun inner() { return x; }\n  return inner;\n}\n";
+    let function =
+        compile(source, &mut gc).unwrap_or_else(|_| panic!("Expected script to compile"));
+    let listing = disassemble_function(function, &gc);
+
+    let headers: Vec<&str> = listing
+        .lines()
+        .filter(|line| line.starts_with("== BEGIN"))
+        .collect();
+    assert_eq!(
+        headers,
+        vec![
+            "== BEGIN <script> ==",
+            "== BEGIN <fn outer> ==",
+            "== BEGIN <fn inner> =="
+        ]
+    );
+    assert!(listing.contains("OP_CLOSURE"));
+    assert!(listing.contains("local 1"));
+    assert!(listing.contains("OP_GET_UPVALUE"));
+}
+
+/// Lines where a debugger paused, with the variables visible at each of them.
+type Pauses = Rc<RefCell<Vec<(usize, Vec<String>)>>>;
+
+/// Debugger that follows a fixed list of commands and records where it paused.
+struct ScriptedDebugger {
+    /// Lines of the script, or of the module with the given path.
+    breakpoints: Vec<(Option<PathBuf>, usize)>,
+    commands: Vec<DebugCommand>,
+    pauses: Pauses,
+}
+
+impl Debugger for ScriptedDebugger {
+    fn has_breakpoint(&self, path: Option<&Path>, line: usize) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|(file, at)| *at == line && file.as_deref() == path)
+    }
+
+    fn paused(&mut self, vm: &Vm, _path: Option<&Path>, line: usize) -> DebugCommand {
+        let locals = vm
+            .locals(0)
+            .into_iter()
+            .chain(vm.upvalues(0))
+            .map(|(name, value)| format!("{}={}", name, GcTraceFormatter::new(value, vm.gc())))
+            .collect();
+        self.pauses.borrow_mut().push((line, locals));
+        if self.commands.is_empty() {
+            DebugCommand::Continue
+        } else {
+            self.commands.remove(0)
+        }
+    }
+}
+
+#[test]
+fn debugger_steps_and_inspects_locals() {
+    let source = "\
+fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+var x = 1;
+var y = add(x, 2);
+print y;
+";
+    let pauses = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = Vm::new();
+    vm.set_debugger(Some(Box::new(ScriptedDebugger {
+        breakpoints: vec![(None, 3)],
+        commands: vec![
+            DebugCommand::StepOver,
+            DebugCommand::StepIn,
+            DebugCommand::StepIn,
+            DebugCommand::Continue,
+            DebugCommand::StepOut,
+        ],
+        pauses: Rc::clone(&pauses),
+    })));
+    vm.interpret(source).unwrap();
+
+    let pauses = pauses.borrow();
+    let lines: Vec<usize> = pauses.iter().map(|(line, _)| *line).collect();
+    // The declaration of `add` is attributed to its closing brace.
+    assert_eq!(lines, vec![4, 5, 6, 2, 3, 7]);
+    assert_eq!(pauses[3].1, vec!["a=1", "b=2"]);
+    assert_eq!(pauses[4].1, vec!["a=1", "b=2", "sum=3"]);
+    assert_eq!(vm.get_global::<f64>("y"), Some(3.0));
+}
+
+#[test]
+fn debugger_inspects_upvalues_and_stops() {
+    let source = "\
+fun counter() {
+  var count = 10;
+  fun inc() {
+    count = count + 1;
+    return count;
+  }
+  return inc;
+}
+counter()();
+";
+    let pauses = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = Vm::new();
+    vm.set_debugger(Some(Box::new(ScriptedDebugger {
+        breakpoints: vec![(None, 5)],
+        commands: vec![DebugCommand::Continue, DebugCommand::Stop],
+        pauses: Rc::clone(&pauses),
+    })));
+    let error = match vm.interpret(source) {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+
+    assert_eq!(error.message, "Stopped by the debugger.");
+    assert_eq!(error.trace[0].line, 5);
+    assert_eq!(pauses.borrow()[1], (5, vec!["count=11".to_owned()]));
+}
+
+#[test]
+fn debugger_stop_is_not_caught() {
+    let source = "\
+var caught = false;
+try {
+  var x = 1;
+} catch (e) {
+  caught = true;
+} finally {
+  caught = true;
+}
+";
+    let mut vm = Vm::new();
+    vm.set_debugger(Some(Box::new(ScriptedDebugger {
+        breakpoints: vec![(None, 3)],
+        commands: vec![DebugCommand::Continue, DebugCommand::Stop],
+        pauses: Rc::new(RefCell::new(Vec::new())),
+    })));
+    assert!(matches!(
+        vm.interpret(source),
+        Err(LoxError::RuntimeError(_))
+    ));
+    assert_eq!(vm.get_global::<bool>("caught"), Some(false));
+}
+
+#[test]
+fn debugger_breakpoints_in_modules() {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("debug_module");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(
+        root.join("module.lox"),
+        "export fun twice(n) {\n  var result = n * 2;\n  return result;\n}\n",
+    )
+    .unwrap();
+    let module = root.join("module.lox").canonicalize().unwrap();
+    let source = "\
+import { twice } from \"module.lox\";
+var x = twice(1);
+var y = twice(x);
+";
+    let config = VmConfig {
+        module_paths: vec![root.clone()],
+        ..VmConfig::trusted()
+    };
+    let pauses = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = Vm::builder().config(config).build();
+    vm.set_debugger(Some(Box::new(ScriptedDebugger {
+        breakpoints: vec![(Some(module), 3)],
+        commands: vec![DebugCommand::Continue],
+        pauses: Rc::clone(&pauses),
+    })));
+    vm.interpret(source).unwrap();
+
+    // Line 3 of the script isn't a breakpoint.
+    let pauses = pauses.borrow();
+    assert_eq!(pauses.len(), 3);
+    assert_eq!(
+        pauses[1],
+        (3, vec!["n=1".to_owned(), "result=2".to_owned()])
+    );
+    assert_eq!(
+        pauses[2],
+        (3, vec!["n=2".to_owned(), "result=4".to_owned()])
+    );
+}
+
+#[test]
+fn profile_counts_calls_and_lines() {
+    let source = "\
+fun square(x) {
+  return x * x;
+}
+var total = 0;
+for (var i = 0; i < 10; i = i + 1) {
+  total = total + square(i);
+}
+";
+    let mut vm = Vm::new();
+    vm.enable_profiling();
+    vm.interpret(source).unwrap();
+    let profile = vm.take_profile().unwrap();
+
+    let square = profile
+        .functions()
+        .into_iter()
+        .find(|f| f.name == "square:2")
+        .unwrap();
+    assert_eq!(square.calls, 10);
+    // GetLocal, GetLocal, Multiply and Return for every call.
+    assert_eq!(square.instructions, 40);
+
+    let line = profile.lines().into_iter().find(|l| l.line == 2).unwrap();
+    assert_eq!(line.instructions, 40);
+
+    let folded = profile.folded();
+    let stacks: Vec<&str> = folded
+        .lines()
+        .map(|line| line.rsplit_once(' ').unwrap().0)
+        .collect();
+    assert_eq!(stacks, vec!["<script>", "<script>;square:2"]);
+    assert!(profile.report().contains("square:2"));
+    assert!(vm.take_profile().is_none());
+}
+
+#[test]
+fn profile_lines_of_modules() {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("profile_module");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(
+        root.join("module.lox"),
+        "export fun square(x) {\n  return x * x;\n}\n",
+    )
+    .unwrap();
+    let module = root.join("module.lox").canonicalize().unwrap();
+    let source = "\
+import { square } from \"module.lox\";
+for (var i = 0; i < 10; i = i + 1) square(i);
+";
+    let config = VmConfig {
+        module_paths: vec![root.clone()],
+        ..VmConfig::trusted()
+    };
+    let mut vm = Vm::builder().config(config).build();
+    vm.enable_profiling();
+    vm.interpret(source).unwrap();
+    let profile = vm.take_profile().unwrap();
+
+    let lines = profile.lines();
+    let line = |path: Option<&Path>| {
+        lines
+            .iter()
+            .find(|l| l.path.as_deref() == path && l.line == 2)
+            .unwrap()
+    };
+    // GetLocal, GetLocal, Multiply and Return for every call.
+    assert_eq!(line(Some(&module)).instructions, 40);
+    assert!(line(None).instructions > 40);
+    assert!(profile
+        .report()
+        .contains(&format!("{}:2", module.display())));
+}
+
+#[test]
+fn chunks_hold_65536_constants() {
+    // Constants aren't deduplicated, so each literal takes a new slot.
+    let mut source = "0;".repeat(65535);
+    source.push_str("var last = true;");
+    let mut vm = Vm::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(vm.get_global::<bool>("last"), Some(true));
+
+    source.push_str("last;");
+    let diagnostics = match vm.interpret(&source) {
+        Err(LoxError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("Expected compile error"),
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Too many constants in one chunk.");
+}
+
+#[test]
+fn functions_hold_65536_locals() {
+    // Nested blocks keep the duplicate checks of each declaration short. The
+    // first slot holds the function itself.
+    let locals: String = (1..65536)
+        .map(|i| format!("{}var v{};", if i % 256 == 0 { "{" } else { "" }, i))
+        .collect();
+    let blocks = "}".repeat(65535 / 256);
+    let source = format!(
+        "fun f() {{ {} v65535 = true; return v65535; {} }} var result = f();",
+        locals, blocks
+    );
+    let mut vm = Vm::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(vm.get_global::<bool>("result"), Some(true));
+
+    let source = format!("fun f() {{ {} var oops; {} }}", locals, blocks);
+    let diagnostics = match vm.interpret(&source) {
+        Err(LoxError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("Expected compile error"),
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].message,
+        "Too many local variables in function."
+    );
+}
+
+#[test]
+fn calls_take_65535_arguments() {
+    let arguments = vec!["a"; 65535].join(",");
+    let source = format!(
+        "fun f() {{}}
+fun g() {{ var a = 1; f({}); }}
+g();",
+        arguments
+    );
+    let mut vm = Vm::new();
+    let error = match vm.interpret(&source) {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+    assert_eq!(error.message, "Expected 0 arguments but got 65535.");
+
+    let source = format!("fun g() {{ var a = 1; f({},a); }}", arguments);
+    let diagnostics = match vm.interpret(&source) {
+        Err(LoxError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("Expected compile error"),
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].message,
+        "Can't have more than 65535 arguments."
+    );
+}
+
+#[test]
+fn list_literals_take_65535_elements() {
+    // Constants aren't deduplicated, so the elements are read from a local.
+    let elements = vec!["a"; 65535].join(",");
+    let source = format!(
+        "fun f() {{ var a = 1; return [{}].len(); }} var n = f();",
+        elements
+    );
+    let mut vm = Vm::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(vm.get_global::<f64>("n"), Some(65535.0));
+
+    let source = format!("fun g() {{ var a = 1; return [{},a]; }}", elements);
+    let diagnostics = match vm.interpret(&source) {
+        Err(LoxError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("Expected compile error"),
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].message,
+        "Can't have more than 65535 elements in a list literal."
+    );
+}
+
+#[test]
+fn map_literals_take_65535_entries() {
+    // Each key increments a local, as constants aren't deduplicated.
+    let entries = vec!["i = i + a: a"; 65535].join(",");
+    let source = format!(
+        "fun f() {{ var a = 1; var i = 0; return {{{}}}.len(); }} var n = f();",
+        entries
+    );
+    let mut vm = Vm::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(vm.get_global::<f64>("n"), Some(65535.0));
+
+    let source = format!(
+        "fun g() {{ var a = 1; var i = 0; return {{{},a:a}}; }}",
+        entries
+    );
+    let diagnostics = match vm.interpret(&source) {
+        Err(LoxError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("Expected compile error"),
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].message,
+        "Can't have more than 65535 entries in a map literal."
+    );
+}
+
+#[test]
+fn strings_interpolate_65535_expressions() {
+    let parts = "${a}".repeat(65535);
+    let source = format!(
+        "fun f() {{ var a = 1; return \"{}\".len(); }} var n = f();",
+        parts
+    );
+    let mut vm = Vm::new();
+    vm.interpret(&source).unwrap();
+    assert_eq!(vm.get_global::<f64>("n"), Some(65535.0));
+
+    let source = format!("fun g() {{ var a = 1; return \"{}${{a}}\"; }}", parts);
+    let diagnostics = match vm.interpret(&source) {
+        Err(LoxError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("Expected compile error"),
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].message,
+        "Too many interpolated expressions in string."
+    );
+}
+
+#[test]
+fn builder_limits_call_depth() {
+    let source = "fun count(n) { if (n == 0) return 0; return 1 + count(n - 1); }";
+    let mut vm = Vm::builder().max_frames(10).build();
+    vm.interpret(source).unwrap();
+    vm.interpret("var result = count(8);").unwrap();
+    assert_eq!(vm.get_global::<f64>("result"), Some(8.0));
+
+    let error = match vm.interpret("count(9);") {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+    assert_eq!(error.message, "Stack overflow.");
+
+    let mut vm = Vm::builder().max_stack(100).build();
+    vm.interpret(source).unwrap();
+    let error = match vm.interpret("count(100);") {
+        Err(LoxError::RuntimeError(error)) => error,
+        _ => panic!("Expected runtime error"),
+    };
+    assert_eq!(error.message, "Stack overflow.");
+}
+
+#[test]
+fn instruction_budget() {
+    let mut vm = Vm::builder().max_instructions(10_000).build();
+    assert!(matches!(
+        vm.interpret("var i = 0; while (true) i = i + 1;"),
+        Err(LoxError::BudgetExceeded(Limit::Instructions))
+    ));
+    let count = vm.get_global::<f64>("i").unwrap();
+    assert!(count > 1000.0 && count < 10_000.0);
+
+    // Each script gets a new budget.
+    vm.interpret("var j = 0; while (j < 500) j = j + 1;")
+        .unwrap();
+    assert_eq!(vm.get_global::<f64>("j"), Some(500.0));
+}
+
+#[test]
+fn budget_errors_are_not_caught() {
+    let mut vm = Vm::builder().max_instructions(10_000).build();
+    let source = "
+var caught = false;
+try {
+  while (true) {}
+} catch (e) {
+  caught = true;
+} finally {
+  caught = true;
+}";
+    assert!(matches!(
+        vm.interpret(source),
+        Err(LoxError::BudgetExceeded(Limit::Instructions))
+    ));
+    assert_eq!(vm.get_global::<bool>("caught"), Some(false));
+}
+
+#[test]
+fn time_budget() {
+    let mut vm = Vm::builder().timeout(Duration::from_millis(50)).build();
+    assert!(matches!(
+        vm.interpret("fun spin() { while (true) {} } spin();"),
+        Err(LoxError::BudgetExceeded(Limit::Time))
+    ));
+    vm.interpret("var done = spin != nil;").unwrap();
+    assert_eq!(vm.get_global::<bool>("done"), Some(true));
+}
+
+#[test]
+fn heap_budget() {
+    let mut vm = Vm::builder().max_heap(4 * 1024 * 1024).build();
+    // Garbage is collected before counting against the limit.
+    vm.interpret("for (var i = 0; i < 100000; i = i + 1) { var l = [i, i, i]; }")
+        .unwrap();
+
+    assert!(matches!(
+        vm.interpret("var s = \"x\"; while (true) s = s + s;"),
+        Err(LoxError::BudgetExceeded(Limit::Memory))
+    ));
+    // The string is still reachable, so it has to be released by the host.
+    assert!(vm.interpret("var ok = true;").is_err());
+    vm.set_global("s", ());
+    vm.interpret("var ok = true;").unwrap();
+    assert_eq!(vm.get_global::<bool>("ok"), Some(true));
+}
+
+#[test]
+fn heap_budget_counts_growing_objects() {
+    let scripts = [
+        "var l = []; for (var i = 0; i < 2000000; i = i + 1) l.push(i);",
+        "var l = []; for (var i = 0; i < 2000000; i = i + 1) l.insert(0, i);",
+        "var m = {}; for (var i = 0; i < 2000000; i = i + 1) m[i] = i;",
+    ];
+    for script in scripts {
+        let mut vm = Vm::builder().max_heap(2 * 1024 * 1024).build();
+        assert!(matches!(
+            vm.interpret(script),
+            Err(LoxError::BudgetExceeded(Limit::Memory))
+        ));
+    }
+}
+
+fn runtime_error_message(vm: &mut Vm, source: &str) -> String {
+    match vm.interpret(source) {
+        Err(LoxError::RuntimeError(error)) => error.message,
+        _ => panic!("Expected runtime error"),
+    }
+}
+
+#[test]
+fn config_selects_native_modules() {
+    let config = VmConfig {
+        natives: vec![NativeModule::Panic],
+        ..VmConfig::trusted()
+    };
+    let mut vm = Vm::builder().config(config).build();
+    assert_eq!(
+        runtime_error_message(&mut vm, "clock();"),
+        "Undefined variable 'clock'."
+    );
+    assert_eq!(runtime_error_message(&mut vm, "panic(1);"), "panic: 1");
+
+    let mut vm = Vm::builder().config(VmConfig::sandboxed()).build();
+    vm.interpret("print clock() >= 0; var ok = true;").unwrap();
+    assert_eq!(vm.get_global::<bool>("ok"), Some(true));
+    assert_eq!(
+        runtime_error_message(&mut vm, "readFile(\"Cargo.toml\");"),
+        "Undefined variable 'readFile'."
+    );
+}
+
+#[test]
+fn config_limits_readable_roots() {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("readable_root");
+    fs::create_dir_all(&root).unwrap();
+    let inside = root.join("inside.txt");
+    fs::write(&inside, "inside").unwrap();
+    let outside = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+    let escape = root.join("..").join("..").join("Cargo.toml");
+
+    let config = VmConfig {
+        readable_roots: Some(vec![root.clone()]),
+        ..VmConfig::trusted()
+    };
+    let mut vm = Vm::builder().config(config).build();
+    vm.set_global("inside", inside.to_str().unwrap());
+    vm.interpret("var content = readFile(inside);").unwrap();
+    assert_eq!(
+        vm.get_global::<String>("content"),
+        Some("inside".to_owned())
+    );
+    for path in [&outside, &escape, &root.join("missing.txt")] {
+        vm.set_global("path", path.to_str().unwrap());
+        assert_eq!(
+            runtime_error_message(&mut vm, "readFile(path);"),
+            format!("Can't read '{}'.", path.to_str().unwrap())
+        );
+    }
+
+    let mut vm = Vm::new();
+    assert_eq!(
+        runtime_error_message(&mut vm, "readFile(\"Cargo.toml\");"),
+        "Undefined variable 'readFile'."
+    );
+
+    let mut vm = Vm::builder().config(VmConfig::trusted()).build();
+    vm.set_global("path", outside.to_str().unwrap());
+    vm.interpret("var content = readFile(path);").unwrap();
+    assert!(vm
+        .get_global::<String>("content")
+        .unwrap()
+        .contains("loxido"));
+}
+
+#[test]
+fn config_module_paths() {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("module_path");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("answer.lox"), "export var answer = 42;").unwrap();
+
+    let config = VmConfig {
+        module_paths: vec![root.clone()],
+        readable_roots: Some(vec![root.clone()]),
+        ..VmConfig::trusted()
+    };
+    let mut vm = Vm::builder().config(config).build();
+    vm.interpret("import { answer } from \"answer.lox\";")
+        .unwrap();
+    assert_eq!(vm.get_global::<f64>("answer"), Some(42.0));
+
+    let mut vm = Vm::new();
+    assert_eq!(
+        runtime_error_message(&mut vm, "import \"answer.lox\" as a;"),
+        "Can't find module 'answer.lox'."
+    );
+
+    let config = VmConfig {
+        module_paths: vec![root],
+        ..VmConfig::sandboxed()
+    };
+    let mut vm = Vm::builder().config(config).build();
+    assert_eq!(
+        runtime_error_message(&mut vm, "import \"answer.lox\" as a;"),
+        "Can't import module 'answer.lox'."
+    );
+}
+
+/// Output sink that can still be read after being moved into a VM.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> String {
+        String::from_utf8(self.0.borrow_mut().split_off(0)).unwrap()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn output_and_errors_are_redirected() {
+    let output = SharedBuffer::default();
+    let errors = SharedBuffer::default();
+    let mut vm = Vm::builder()
+        .output(output.clone())
+        .error_output(errors.clone())
+        .build();
+
+    vm.interpret("print 1 + 2;\nprint \"two\";").unwrap();
+    assert_eq!(output.take(), "3\ntwo\n");
+    assert_eq!(errors.take(), "");
+
+    assert!(vm.interpret("print 1 +;").is_err());
+    assert_eq!(errors.take(), "[line 1] Error at ';': Expect expression.\n");
+
+    assert!(vm.interpret("print \"before\";\nnil();").is_err());
+    assert_eq!(output.take(), "before\n");
+    assert_eq!(
+        errors.take(),
+        "Can only call functions and classes.\n[line 2] in script\n"
+    );
+}
+
+#[test]
+fn errors_are_reported_with_their_source() {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("show_source");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("boom.lox"), "export fun boom() {\n  nil();\n}\n").unwrap();
+    let module = root.join("boom.lox").canonicalize().unwrap();
+    let errors = SharedBuffer::default();
+    let config = VmConfig {
+        module_paths: vec![root.clone()],
+        ..VmConfig::trusted()
+    };
+    let mut vm = Vm::builder()
+        .config(config)
+        .error_output(errors.clone())
+        .show_source(true)
+        .build();
+
+    assert!(vm.interpret("print 1 +;").is_err());
+    assert_eq!(
+        errors.take(),
+        "[line 1] Error at ';': Expect expression.\n1 | print 1 +;\n  |          ^\n"
+    );
+
+    // The error is shown in the source of the module.
+    assert!(vm
+        .interpret("var s = \"éééé\";\nimport { boom } from \"boom.lox\";\nboom();")
+        .is_err());
+    assert_eq!(
+        errors.take(),
+        format!(
+            "Can only call functions and classes.\n2 |   nil();\n  |   ^^^^^\n\
+             [{} line 2] in boom()\n[line 3] in script\n",
+            module.display()
+        )
+    );
+}
+
+#[test]
+fn sandboxed_print_is_discarded() {
+    let output = SharedBuffer::default();
+    let mut vm = Vm::builder()
+        .config(VmConfig::sandboxed())
+        .output(output.clone())
+        .build();
+    vm.interpret("print \"hidden\";").unwrap();
+    assert_eq!(output.take(), "");
+}
@@ -1,5 +1,8 @@
 use std::path::PathBuf;
-use std::{env, fs, process::Command};
+use std::{
+    env, fs,
+    process::{Command, Output},
+};
 
 use regex::Regex;
 
@@ -79,9 +82,63 @@ fn run_file_test(filename: &str) {
     let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     path.push(filename);
     let expected = parse_comments(&path);
+    let output = loxido_command().arg(path).output().unwrap();
+    check_output(expected, output);
+}
+
+#[test_resources("tests/integration/*/*.lox")]
+fn run_compiled_file_test(filename: &str) {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push(filename);
+    let expected = parse_comments(&path);
 
+    let mut compiled = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    compiled.push(filename.replace('/', "_"));
+    compiled.set_extension("loxc");
+    let output = loxido_command()
+        .arg("compile")
+        .arg(&path)
+        .arg("-o")
+        .arg(&compiled)
+        .output()
+        .unwrap();
+    if !output.status.success() {
+        check_output(expected, output);
+        return;
+    }
+
+    // Modules are still imported from the directory of the source file.
+    let output = loxido_command()
+        .arg(compiled)
+        .env("LOX_PATH", path.parent().unwrap())
+        .output()
+        .unwrap();
+    check_output(expected, output);
+}
+
+/// Like the `no_reuse_constants` test of clox, with enough constants to go
+/// past the limit of 65536 per chunk. The script is too large to keep in the
+/// repository, so it's generated.
+#[test]
+fn no_reuse_constants() {
+    let mut source = String::from("fun f() {\n");
+    for line in 0..65536 / 8 {
+        let constants: Vec<String> = (line * 8..line * 8 + 8)
+            .map(|i| format!("{};", i))
+            .collect();
+        source.push_str(&format!("  {}\n", constants.join(" ")));
+    }
+    source.push_str("\n  1; // Error at '1': Too many constants in one chunk.\n}\n");
+
+    let mut path = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    path.push("limit_no_reuse_constants.lox");
+    fs::write(&path, source).unwrap();
+    let expected = parse_comments(&path);
     let output = loxido_command().arg(path).output().unwrap();
+    check_output(expected, output);
+}
 
+fn check_output(expected: Expected, output: Output) {
     let out: Vec<String> = String::from_utf8(output.stdout)
         .unwrap()
         .lines()
@@ -116,8 +173,13 @@ fn run_file_test(filename: &str) {
 
     if let Some(e) = expected.runtime_err {
         assert_eq!(e.message, err[0], "Runtime error should match");
+        // Frames in imported modules start with the path of the module.
+        let frame = err[1..]
+            .iter()
+            .find(|frame| frame.starts_with("[line "))
+            .expect("Runtime error should have a frame in the script");
         assert_eq!(
-            err[1][0..e.line_prefix.len()],
+            frame[0..e.line_prefix.len()],
             e.line_prefix,
             "Runtime error line should match"
         );